@@ -68,6 +68,21 @@ VAR alpha ups.timer.start "-60"
 VAR alpha ups.vendorid "0764"
 END LIST VAR alpha
 """
+COMMAND_RW_LIST = "list rw"  # Plus UPS name
+DATA_RW_LIST = """\
+BEGIN LIST RW alpha
+RW alpha battery.charge.low "10"
+RW alpha ups.delay.shutdown "60"
+END LIST RW alpha
+"""
+COMMAND_CMD_LIST = "list cmd"  # Plus UPS name
+DATA_CMD_LIST = """\
+BEGIN LIST CMD alpha
+CMD alpha beeper.disable
+CMD alpha beeper.enable
+CMD alpha test.battery.start.quick
+END LIST CMD alpha
+"""
 
 
 class EmptyObject:
@@ -179,6 +194,16 @@ def handleRequest(line, client):
             sendText(DATA_VAR_LIST)
         else:
             sendText("ERR UPS not found\n")
+    elif numLineParts == 3 and lowerLine.startswith("list rw"):
+        if lineParts[2] == UPS_EXPECTED:
+            sendText(DATA_RW_LIST)
+        else:
+            sendText("ERR UPS not found\n")
+    elif numLineParts == 3 and lowerLine.startswith("list cmd"):
+        if lineParts[2] == UPS_EXPECTED:
+            sendText(DATA_CMD_LIST)
+        else:
+            sendText("ERR UPS not found\n")
     elif numLineParts == 1 and lowerLine.startswith("logout"):
         sendText(DATA_VER)
     else:
@@ -0,0 +1,81 @@
+use std::io::Write;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::common::ErrorResult;
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl ContentEncoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            ContentEncoding::Zstd => "zstd",
+        }
+    }
+}
+
+// Pick the supported encoding with the highest quality in the Accept-Encoding header, if any.
+// Zstd (if built with it) is preferred over gzip for equal qualities, and "*" matches any supported encoding.
+pub fn negotiate_content_encoding(accept_encoding_str: &str) -> Option<ContentEncoding> {
+    let mut qualities: Vec<(String, f32)> = Vec::new();
+    for coding in accept_encoding_str.split(',') {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("").to_lowercase();
+        if name.is_empty() {
+            continue;
+        }
+        let quality = parts
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .map_or(1f32, |(_, value)| value.trim().parse::<f32>().unwrap_or(0f32));
+        qualities.push((name, quality));
+    }
+    let quality_of = |encoding: ContentEncoding| qualities.iter()
+        .find(|(name, _)| name == encoding.name())
+        .or_else(|| qualities.iter().find(|(name, _)| name == "*"))
+        .map_or(0f32, |(_, quality)| *quality);
+
+    let mut best_encoding = None;
+    let mut best_quality = 0f32;
+    for encoding in supported_encodings() {
+        let quality = quality_of(encoding);
+        if quality > best_quality {
+            best_encoding = Some(encoding);
+            best_quality = quality;
+        }
+    }
+
+    best_encoding
+}
+
+// In order of preference
+fn supported_encodings() -> Vec<ContentEncoding> {
+    vec![
+        #[cfg(feature = "zstd")]
+        ContentEncoding::Zstd,
+        ContentEncoding::Gzip,
+    ]
+}
+
+pub fn compress(encoding: ContentEncoding, content: &[u8]) -> ErrorResult<Vec<u8>> {
+    match encoding {
+        ContentEncoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content)?;
+            Ok(encoder.finish()?)
+        },
+        #[cfg(feature = "zstd")]
+        ContentEncoding::Zstd => Ok(zstd::stream::encode_all(content, ZSTD_LEVEL)?),
+    }
+}
@@ -1,4 +1,16 @@
+use std::collections::BTreeMap;
 use std::net::{IpAddr, Ipv6Addr};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use url::Url;
+
+use crate::common::ErrorResult;
+use crate::target_allowlist::{TargetAllowlist, TargetAllowlistFile};
+use crate::targets::{NutTarget, NutTargetFile};
+use crate::web_config::WebConfig;
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -6,6 +18,53 @@ pub struct Config {
     pub http_port: u16,
     pub http_path: String,
     pub print_metrics_and_exit: bool,
+    pub config_file: Option<String>,
+    pub label_rules: Vec<LabelRule>,
+    pub var_labels: BTreeMap<String, String>,
+    pub targets: BTreeMap<String, NutTarget>,
+    pub target_allowlist: Option<TargetAllowlist>,
+    // Target names (for named targets) or addresses
+    pub poll_targets: Vec<String>,
+    pub poll_interval: Duration,
+    pub push_url: Option<Url>,
+    pub push_format: PushFormat,
+    pub push_job: String,
+    pub push_retries: u32,
+    pub mqtt_url: Option<Url>,
+    pub mqtt_client_id: String,
+    pub mqtt_topic_prefix: String,
+    pub mqtt_discovery_prefix: String,
+    pub webhook_urls: Vec<Url>,
+    pub webhook_retries: u32,
+    pub energy_state_file: Option<String>,
+    pub ready_max_scrape_age: Option<Duration>,
+    pub web_config_file: Option<String>,
+    pub web_config: WebConfig,
+    pub sd_file: Option<String>,
+    pub sd_file_interval: Duration,
+    pub sd_file_ups: bool,
+    pub target_timeout: Duration,
+    pub target_max_connections: usize,
+    // Requests per second
+    pub http_rate_limit: Option<f64>,
+    pub scrape_cache_ttl: Option<Duration>,
+    pub http_compression: bool,
+    // Smaller responses aren't compressed
+    pub http_compression_min_size: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PushFormat {
+    Pushgateway,
+    RemoteWrite,
+}
+
+// Static labels to add to all metrics for matching targets and UPSes
+#[derive(Debug, Clone)]
+pub struct LabelRule {
+    pub target: Option<Regex>,
+    pub ups: Option<Regex>,
+    pub labels: BTreeMap<String, String>,
 }
 
 impl Config {
@@ -16,6 +75,55 @@ impl Config {
     const DEFAULT_HTTP_PORT: u16 = 9995;
     const DEFAULT_HTTP_PATH: &'static str = "/nut";
     const DEFAULT_PRINT_METRICS_AND_EXIT: bool = false;
+    const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(15);
+    const DEFAULT_PUSH_FORMAT: PushFormat = PushFormat::Pushgateway;
+    const DEFAULT_PUSH_JOB: &'static str = "nut";
+    const DEFAULT_PUSH_RETRIES: u32 = 3;
+    const DEFAULT_MQTT_CLIENT_ID: &'static str = "prometheus-nut-exporter";
+    const DEFAULT_MQTT_TOPIC_PREFIX: &'static str = "nut";
+    const DEFAULT_MQTT_DISCOVERY_PREFIX: &'static str = "homeassistant";
+    const DEFAULT_WEBHOOK_RETRIES: u32 = 3;
+    const DEFAULT_SD_FILE_INTERVAL: Duration = Duration::from_secs(60);
+    const DEFAULT_SD_FILE_UPS: bool = false;
+    const DEFAULT_TARGET_TIMEOUT: Duration = Duration::from_secs(10);
+    const DEFAULT_TARGET_MAX_CONNECTIONS: usize = 2;
+    const DEFAULT_HTTP_COMPRESSION: bool = true;
+    const DEFAULT_HTTP_COMPRESSION_MIN_SIZE: usize = 1024;
+
+    // Find the named target with this name, else parse it as an address.
+    pub fn resolve_target(&self, target_raw: &str) -> ErrorResult<NutTarget> {
+        if let Some(target) = self.targets.get(target_raw) {
+            return Ok(target.clone());
+        }
+        let mut target = NutTarget::from_address(parse_target_address(target_raw)?, self.target_timeout, self.target_max_connections);
+        let is_allowlisted = match &self.target_allowlist {
+            Some(allowlist) => allowlist.restricts_hosts() && allowlist.is_allowed(&target.address),
+            None => false,
+        };
+        target.is_known = self.poll_targets.contains(&target.name) || is_allowlisted;
+        Ok(target)
+    }
+}
+
+// Labels used by the metrics themselves (or as InfluxDB tags or push labels), which can't be overridden
+const RESERVED_LABELS: [&str; 6] = ["ups", "status", "result", "server", "job", "instance"];
+
+// Structure of the YAML config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    labels: Vec<LabelRuleFile>,
+    var_labels: BTreeMap<String, String>,
+    targets: BTreeMap<String, NutTargetFile>,
+    target_allowlist: Option<TargetAllowlistFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LabelRuleFile {
+    target: Option<String>,
+    ups: Option<String>,
+    labels: BTreeMap<String, String>,
 }
 
 pub fn read_config() -> Config {
@@ -24,6 +132,36 @@ pub fn read_config() -> Config {
         http_port: Config::DEFAULT_HTTP_PORT,
         http_path: Config::DEFAULT_HTTP_PATH.to_owned(),
         print_metrics_and_exit: Config::DEFAULT_PRINT_METRICS_AND_EXIT,
+        config_file: None,
+        label_rules: Vec::new(),
+        var_labels: BTreeMap::new(),
+        targets: BTreeMap::new(),
+        target_allowlist: None,
+        poll_targets: Vec::new(),
+        poll_interval: Config::DEFAULT_POLL_INTERVAL,
+        push_url: None,
+        push_format: Config::DEFAULT_PUSH_FORMAT,
+        push_job: Config::DEFAULT_PUSH_JOB.to_owned(),
+        push_retries: Config::DEFAULT_PUSH_RETRIES,
+        mqtt_url: None,
+        mqtt_client_id: Config::DEFAULT_MQTT_CLIENT_ID.to_owned(),
+        mqtt_topic_prefix: Config::DEFAULT_MQTT_TOPIC_PREFIX.to_owned(),
+        mqtt_discovery_prefix: Config::DEFAULT_MQTT_DISCOVERY_PREFIX.to_owned(),
+        webhook_urls: Vec::new(),
+        webhook_retries: Config::DEFAULT_WEBHOOK_RETRIES,
+        energy_state_file: None,
+        ready_max_scrape_age: None,
+        web_config_file: None,
+        web_config: WebConfig::default(),
+        sd_file: None,
+        sd_file_interval: Config::DEFAULT_SD_FILE_INTERVAL,
+        sd_file_ups: Config::DEFAULT_SD_FILE_UPS,
+        target_timeout: Config::DEFAULT_TARGET_TIMEOUT,
+        target_max_connections: Config::DEFAULT_TARGET_MAX_CONNECTIONS,
+        http_rate_limit: None,
+        scrape_cache_ttl: None,
+        http_compression: Config::DEFAULT_HTTP_COMPRESSION,
+        http_compression_min_size: Config::DEFAULT_HTTP_COMPRESSION_MIN_SIZE,
     };
 
     if let Ok(http_address_str) = std::env::var("HTTP_ADDRESS") {
@@ -47,6 +185,256 @@ pub fn read_config() -> Config {
             config.print_metrics_and_exit = print_metrics_and_exit;
         }
     }
+    if let Ok(config_file) = std::env::var("CONFIG_FILE") {
+        if !config_file.is_empty() {
+            config.config_file = Some(config_file);
+        }
+    }
+    if let Ok(poll_targets_str) = std::env::var("POLL_TARGETS") {
+        // Resolved after reading the config file, which may contain named targets
+        config.poll_targets = poll_targets_str.split(',').map(str::trim).filter(|x| !x.is_empty()).map(str::to_owned).collect();
+    }
+    if let Ok(poll_interval_str) = std::env::var("POLL_INTERVAL") {
+        if let Ok(poll_interval) = poll_interval_str.parse::<f64>() {
+            if poll_interval > 0f64 {
+                config.poll_interval = Duration::from_secs_f64(poll_interval);
+            }
+        }
+    }
+    if let Ok(push_url_str) = std::env::var("PUSH_URL") {
+        if let Ok(push_url) = Url::parse(&push_url_str) {
+            config.push_url = Some(push_url);
+        }
+    }
+    if let Ok(push_format_str) = std::env::var("PUSH_FORMAT") {
+        match push_format_str.as_str() {
+            "pushgateway" => config.push_format = PushFormat::Pushgateway,
+            "remote_write" => config.push_format = PushFormat::RemoteWrite,
+            _ => {},
+        }
+    }
+    if let Ok(push_job) = std::env::var("PUSH_JOB") {
+        if !push_job.is_empty() {
+            config.push_job = push_job;
+        }
+    }
+    if let Ok(push_retries_str) = std::env::var("PUSH_RETRIES") {
+        if let Ok(push_retries) = push_retries_str.parse::<u32>() {
+            config.push_retries = push_retries;
+        }
+    }
+    if let Ok(mqtt_url_str) = std::env::var("MQTT_URL") {
+        if let Ok(mqtt_url) = Url::parse(&mqtt_url_str) {
+            if mqtt_url.scheme() == "mqtt" && mqtt_url.host_str().is_some() {
+                config.mqtt_url = Some(mqtt_url);
+            }
+        }
+    }
+    if let Ok(mqtt_client_id) = std::env::var("MQTT_CLIENT_ID") {
+        if !mqtt_client_id.is_empty() {
+            config.mqtt_client_id = mqtt_client_id;
+        }
+    }
+    if let Ok(mqtt_topic_prefix) = std::env::var("MQTT_TOPIC_PREFIX") {
+        if !mqtt_topic_prefix.is_empty() {
+            config.mqtt_topic_prefix = mqtt_topic_prefix.trim_end_matches('/').to_owned();
+        }
+    }
+    if let Ok(mqtt_discovery_prefix) = std::env::var("MQTT_DISCOVERY_PREFIX") {
+        if !mqtt_discovery_prefix.is_empty() {
+            config.mqtt_discovery_prefix = mqtt_discovery_prefix.trim_end_matches('/').to_owned();
+        }
+    }
+    if let Ok(webhook_urls_str) = std::env::var("WEBHOOK_URLS") {
+        for webhook_url_raw in webhook_urls_str.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            match Url::parse(webhook_url_raw) {
+                Ok(webhook_url) => config.webhook_urls.push(webhook_url),
+                Err(err) => log::warn!("Ignoring webhook URL \"{}\": {}", webhook_url_raw, err),
+            }
+        }
+    }
+    if let Ok(webhook_retries_str) = std::env::var("WEBHOOK_RETRIES") {
+        if let Ok(webhook_retries) = webhook_retries_str.parse::<u32>() {
+            config.webhook_retries = webhook_retries;
+        }
+    }
+    if let Ok(energy_state_file) = std::env::var("ENERGY_STATE_FILE") {
+        if !energy_state_file.is_empty() {
+            config.energy_state_file = Some(energy_state_file);
+        }
+    }
+    if let Ok(ready_max_scrape_age_str) = std::env::var("READY_MAX_SCRAPE_AGE") {
+        if let Ok(ready_max_scrape_age) = ready_max_scrape_age_str.parse::<f64>() {
+            if ready_max_scrape_age > 0f64 {
+                config.ready_max_scrape_age = Some(Duration::from_secs_f64(ready_max_scrape_age));
+            }
+        }
+    }
+    if let Ok(web_config_file) = std::env::var("WEB_CONFIG_FILE") {
+        if !web_config_file.is_empty() {
+            config.web_config_file = Some(web_config_file);
+        }
+    }
+    if let Ok(sd_file) = std::env::var("SD_FILE") {
+        if !sd_file.is_empty() {
+            config.sd_file = Some(sd_file);
+        }
+    }
+    if let Ok(sd_file_interval_str) = std::env::var("SD_FILE_INTERVAL") {
+        if let Ok(sd_file_interval) = sd_file_interval_str.parse::<f64>() {
+            if sd_file_interval > 0f64 {
+                config.sd_file_interval = Duration::from_secs_f64(sd_file_interval);
+            }
+        }
+    }
+    if let Ok(sd_file_ups_str) = std::env::var("SD_FILE_UPS") {
+        if let Ok(sd_file_ups) = sd_file_ups_str.parse::<bool>() {
+            config.sd_file_ups = sd_file_ups;
+        }
+    }
+    if let Ok(target_timeout_str) = std::env::var("TARGET_TIMEOUT") {
+        if let Ok(target_timeout) = target_timeout_str.parse::<f64>() {
+            if target_timeout > 0f64 {
+                config.target_timeout = Duration::from_secs_f64(target_timeout);
+            }
+        }
+    }
+    if let Ok(target_max_connections_str) = std::env::var("TARGET_MAX_CONNECTIONS") {
+        if let Ok(target_max_connections) = target_max_connections_str.parse::<usize>() {
+            if target_max_connections > 0 {
+                config.target_max_connections = target_max_connections;
+            }
+        }
+    }
+    if let Ok(http_rate_limit_str) = std::env::var("HTTP_RATE_LIMIT") {
+        if let Ok(http_rate_limit) = http_rate_limit_str.parse::<f64>() {
+            if http_rate_limit > 0f64 && http_rate_limit.is_finite() {
+                config.http_rate_limit = Some(http_rate_limit);
+            }
+        }
+    }
+    if let Ok(scrape_cache_ttl_str) = std::env::var("SCRAPE_CACHE_TTL") {
+        if let Ok(scrape_cache_ttl) = scrape_cache_ttl_str.parse::<f64>() {
+            if scrape_cache_ttl > 0f64 {
+                config.scrape_cache_ttl = Some(Duration::from_secs_f64(scrape_cache_ttl));
+            }
+        }
+    }
+    if let Ok(http_compression_str) = std::env::var("HTTP_COMPRESSION") {
+        if let Ok(http_compression) = http_compression_str.parse::<bool>() {
+            config.http_compression = http_compression;
+        }
+    }
+    if let Ok(http_compression_min_size_str) = std::env::var("HTTP_COMPRESSION_MIN_SIZE") {
+        if let Ok(http_compression_min_size) = http_compression_min_size_str.parse::<usize>() {
+            config.http_compression_min_size = http_compression_min_size;
+        }
+    }
 
     config
 }
+
+// Read the config file (if any) into the config.
+pub fn read_config_file(config: &mut Config) -> ErrorResult<()> {
+    let path = match &config.config_file {
+        Some(path) => path.clone(),
+        None => return Ok(()),
+    };
+
+    log::debug!("Reading config file: {}", path);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) => return Err(format!("Failed to read config file \"{}\": {}", path, err).into()),
+    };
+    let config_file: ConfigFile = match serde_yaml::from_str(&content) {
+        Ok(config_file) => config_file,
+        Err(err) => return Err(format!("Failed to parse config file \"{}\": {}", path, err).into()),
+    };
+
+    let mut label_rules = Vec::new();
+    for rule in config_file.labels {
+        for label in rule.labels.keys() {
+            check_label_name(label)?;
+        }
+        label_rules.push(LabelRule {
+            target: rule.target.as_deref().map(compile_anchored_regex).transpose()?,
+            ups: rule.ups.as_deref().map(compile_anchored_regex).transpose()?,
+            labels: rule.labels,
+        });
+    }
+    for label in config_file.var_labels.keys() {
+        check_label_name(label)?;
+    }
+
+    let mut targets = BTreeMap::new();
+    for (name, target_file) in config_file.targets {
+        let target = NutTarget::from_file(&name, target_file, config.target_timeout, config.target_max_connections)?;
+        targets.insert(name, target);
+    }
+    let target_allowlist = config_file.target_allowlist.map(TargetAllowlist::from_file).transpose()?;
+
+    config.label_rules = label_rules;
+    config.var_labels = config_file.var_labels;
+    config.targets = targets;
+    config.target_allowlist = target_allowlist;
+
+    Ok(())
+}
+
+pub fn check_label_name(label: &str) -> ErrorResult<()> {
+    lazy_static! {
+        static ref LABEL_NAME_PATTERN: Regex = Regex::new(r#"^[a-zA-Z_][a-zA-Z0-9_]*$"#).unwrap();
+    }
+
+    if !LABEL_NAME_PATTERN.is_match(label) || label.starts_with("__") {
+        return Err(format!("Invalid label name in config file: {}", label).into());
+    }
+    if RESERVED_LABELS.contains(&label) {
+        return Err(format!("Reserved label name in config file: {}", label).into());
+    }
+
+    Ok(())
+}
+
+// Regexes must match the full value, like Prometheus relabeling.
+pub fn compile_anchored_regex(pattern: &str) -> ErrorResult<Regex> {
+    match Regex::new(&format!("^(?:{})$", pattern)) {
+        Ok(regex) => Ok(regex),
+        Err(err) => Err(format!("Invalid regex in config file \"{}\": {}", pattern, err).into()),
+    }
+}
+
+// Resolve the poll targets to target names or parsed addresses, dropping invalid ones.
+pub fn resolve_poll_targets(config: &mut Config) {
+    let mut poll_targets = Vec::new();
+    for target_raw in config.poll_targets.iter() {
+        match config.resolve_target(target_raw) {
+            Ok(target) => poll_targets.push(target.name),
+            Err(err) => log::warn!("Ignoring poll target \"{}\": {}", target_raw, err),
+        }
+    }
+    config.poll_targets = poll_targets;
+}
+
+// Parse a NUT server address, with optional port number.
+pub fn parse_target_address(target_raw: &str) -> ErrorResult<String> {
+    lazy_static! {
+        // Match domain, IPv4 address or IPv6 addres, with optional port number
+        static ref TARGET_PATTERN: Regex = Regex::new(r#"^(?P<host>\[[^\]]+\]|[^:]+)(?::(?P<port>[0-9]+))?$"#).unwrap();
+    }
+
+    let default_port = Config::DEFAULT_NUT_PORT.to_string();
+    let target = match TARGET_PATTERN.captures(target_raw) {
+        Some(captures) => {
+            let host = captures.name("host").unwrap().as_str();
+            let port = match captures.name("port") {
+                Some(port) => port.as_str(),
+                None => default_port.as_str(),
+            };
+            format!("{}:{}", host, port)
+        },
+        None => return Err("Malformed target.".into()),
+    };
+
+    Ok(target)
+}
@@ -0,0 +1,59 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::common::ErrorResult;
+use crate::config::{Config, read_config, read_config_file, resolve_poll_targets};
+use crate::metrics::{CONFIG_LAST_RELOAD_SUCCESSFUL_METRIC, Metric};
+use crate::nut_client::clear_in_flight_scrapes;
+use crate::openmetrics_builder::Sample;
+use crate::scrape_cache::clear_scrape_cache;
+use crate::web_config::{clear_basic_auth_cache, read_web_config_file};
+
+// The current config, replaced on reload.
+// Users take a snapshot (see current_config), so in-flight requests and polls keep using the config they started with.
+pub type SharedConfig = Arc<RwLock<Arc<Config>>>;
+
+static LAST_RELOAD_SUCCESSFUL: AtomicBool = AtomicBool::new(true);
+
+// Read the config files (if any) into the config, after the environment variables.
+pub fn read_config_files(config: &mut Config) -> ErrorResult<()> {
+    read_config_file(config)?;
+    resolve_poll_targets(config);
+    read_web_config_file(config)?;
+    Ok(())
+}
+
+pub fn current_config(shared_config: &SharedConfig) -> Arc<Config> {
+    shared_config.read().unwrap().clone()
+}
+
+// Re-read and validate the config and replace the current config, or keep the current config if invalid.
+// Settings which are only used at startup (like the HTTP address, poll interval and TLS settings) require a restart.
+pub fn reload_config(shared_config: &SharedConfig) -> ErrorResult<()> {
+    log::info!("Reloading config.");
+    let mut config = read_config();
+    let result = read_config_files(&mut config);
+    LAST_RELOAD_SUCCESSFUL.store(result.is_ok(), Ordering::Relaxed);
+    match result {
+        Ok(()) => {
+            let mut current_config = shared_config.write().unwrap();
+            if config.web_config.basic_auth_users != current_config.web_config.basic_auth_users {
+                clear_basic_auth_cache();
+            }
+            *current_config = Arc::new(config);
+            clear_scrape_cache();
+            clear_in_flight_scrapes();
+            log::info!("Reloaded config.");
+            Ok(())
+        },
+        Err(err) => {
+            log::error!("Failed to reload config, keeping the current config: {}", err);
+            Err(err)
+        },
+    }
+}
+
+pub fn build_config_reload_family() -> (&'static Metric, Vec<Sample>) {
+    let value = if LAST_RELOAD_SUCCESSFUL.load(Ordering::Relaxed) { 1f64 } else { 0f64 };
+    (&CONFIG_LAST_RELOAD_SUCCESSFUL_METRIC, vec![Sample { labels: Vec::new(), value }])
+}
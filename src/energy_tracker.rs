@@ -0,0 +1,181 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+
+use crate::common::ErrorResult;
+use crate::labels::UpsLabelMap;
+use crate::metrics::{OUTPUT_ENERGY_METRIC, VarMap};
+use crate::openmetrics_builder::{MetricFamilies, Sample, build_ups_labels, estimate_power};
+use crate::poller::PollResult;
+
+// Energy counter for a single UPS, tracked across polls
+#[derive(Default)]
+struct UpsEnergyCounter {
+    joules: f64,
+    // Power and time of the last poll, if the power was known
+    last_power: Option<(f64, Instant)>,
+}
+
+// Persisted counters, per target and UPS
+type EnergyState = BTreeMap<String, BTreeMap<String, f64>>;
+
+lazy_static! {
+    // Energy counters per target and UPS
+    static ref ENERGY_COUNTERS: Mutex<HashMap<String, BTreeMap<String, UpsEnergyCounter>>> = Mutex::new(HashMap::new());
+}
+
+// Update the energy counters from a poll, using the average power since the last poll.
+// Targets and UPSes missing from the poll (e.g. failed) or without power start over at the next poll,
+// so the energy between failed polls is not counted.
+pub fn track_energy(results: &[PollResult]) {
+    let now = Instant::now();
+    let mut energy_counters = ENERGY_COUNTERS.lock().unwrap();
+    for (target, target_counters) in energy_counters.iter_mut() {
+        let upses = results.iter().find(|result| result.target == *target).map(|result| &result.upses);
+        for (ups, counter) in target_counters.iter_mut() {
+            if !matches!(upses, Some(upses) if upses.contains_key(ups)) {
+                counter.last_power = None;
+            }
+        }
+    }
+    for result in results.iter() {
+        let target_counters = energy_counters.entry(result.target.clone()).or_default();
+        for (ups, vars) in result.upses.iter() {
+            let power = match output_power(vars) {
+                Some(power) => power,
+                None => {
+                    if let Some(counter) = target_counters.get_mut(ups) {
+                        counter.last_power = None;
+                    }
+                    continue;
+                },
+            };
+            let counter = target_counters.entry(ups.clone()).or_default();
+            if let Some((last_power, last_time)) = counter.last_power {
+                counter.joules += (last_power + power) / 2.0 * now.duration_since(last_time).as_secs_f64();
+            }
+            counter.last_power = Some((power, now));
+        }
+    }
+}
+
+// Build the energy counter family for a target, if it's polled or was loaded from the state file, for the UPSes in the label map.
+pub fn build_energy_families(target: &str, ups_labels: &UpsLabelMap) -> MetricFamilies {
+    let energy_counters = ENERGY_COUNTERS.lock().unwrap();
+    let target_counters = match energy_counters.get(target) {
+        Some(target_counters) => target_counters,
+        None => return Vec::new(),
+    };
+
+    let samples: Vec<Sample> = target_counters.iter()
+        .filter(|(ups, _)| ups_labels.contains_key(*ups))
+        .map(|(ups, counter)| Sample { labels: build_ups_labels(ups, ups_labels), value: counter.joules })
+        .collect();
+    match samples.is_empty() {
+        true => Vec::new(),
+        false => vec![(&OUTPUT_ENERGY_METRIC, samples)],
+    }
+}
+
+// Load the counters from the state file (if it exists), to continue counting after restarts.
+pub fn load_energy_state(path: &str) -> ErrorResult<()> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(format!("Failed to read energy state file \"{}\": {}", path, err).into()),
+    };
+    let state: EnergyState = match serde_json::from_str(&content) {
+        Ok(state) => state,
+        Err(err) => return Err(format!("Failed to parse energy state file \"{}\": {}", path, err).into()),
+    };
+
+    let mut energy_counters = ENERGY_COUNTERS.lock().unwrap();
+    for (target, ups_joules) in state.into_iter() {
+        let target_counters = energy_counters.entry(target).or_default();
+        for (ups, joules) in ups_joules.into_iter() {
+            target_counters.entry(ups).or_default().joules = joules;
+        }
+    }
+
+    Ok(())
+}
+
+// Save the counters to the state file, replacing it atomically.
+pub fn save_energy_state(path: &str) -> ErrorResult<()> {
+    let state: EnergyState = ENERGY_COUNTERS.lock().unwrap().iter()
+        .map(|(target, target_counters)| (target.clone(), target_counters.iter().map(|(ups, counter)| (ups.clone(), counter.joules)).collect()))
+        .collect();
+    let content = serde_json::to_string_pretty(&state)?;
+
+    let temp_path = format!("{}.tmp", path);
+    if let Err(err) = std::fs::write(&temp_path, content).and_then(|_| std::fs::rename(&temp_path, path)) {
+        return Err(format!("Failed to write energy state file \"{}\": {}", path, err).into());
+    }
+
+    Ok(())
+}
+
+// Get the output real power in watts, estimated from the load if the UPS doesn't report it.
+fn output_power(vars: &VarMap) -> Option<f64> {
+    match vars.get("ups.realpower").and_then(|value| value.parse::<f64>().ok()).filter(|value| value.is_finite()) {
+        Some(real_power) => Some(real_power),
+        None => estimate_power(vars, "ups.realpower.nominal"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use crate::labels::UpsLabelMap;
+    use crate::metrics::{UpsVarMap, VarMap};
+    use crate::poller::PollResult;
+
+    use super::{build_energy_families, track_energy};
+
+    fn build_poll_result(target: &str) -> PollResult {
+        let vars: VarMap = HashMap::from([("ups.realpower".to_owned(), "100".to_owned())]);
+        let upses: UpsVarMap = HashMap::from([("alpha".to_owned(), vars)]);
+        let ups_labels: UpsLabelMap = HashMap::from([("alpha".to_owned(), Vec::new())]);
+        PollResult { target: target.to_owned(), upses, nut_version: "2.8.0".to_owned(), ups_labels }
+    }
+
+    fn joules(target: &str) -> f64 {
+        let ups_labels: UpsLabelMap = HashMap::from([("alpha".to_owned(), Vec::new())]);
+        build_energy_families(target, &ups_labels)[0].1[0].value
+    }
+
+    #[test]
+    fn energy_is_integrated_between_polls() {
+        let results = vec![build_poll_result("energy-test-ok:3493")];
+        track_energy(&results);
+        std::thread::sleep(Duration::from_millis(20));
+        track_energy(&results);
+
+        // 100 W for at least 20 ms
+        assert!(joules("energy-test-ok:3493") >= 2f64);
+    }
+
+    #[test]
+    fn energy_is_not_integrated_over_failed_polls() {
+        let result = build_poll_result("energy-test-failed:3493");
+        let other_result = build_poll_result("energy-test-other:3493");
+        track_energy(&[result.clone(), other_result.clone()]);
+        // The target failed, so it's missing from the results
+        track_energy(&[other_result]);
+        std::thread::sleep(Duration::from_millis(20));
+        track_energy(std::slice::from_ref(&result));
+        assert_eq!(joules("energy-test-failed:3493"), 0f64);
+
+        // The UPS stopped reporting the power
+        let mut result_without_power = result.clone();
+        result_without_power.upses.get_mut("alpha").unwrap().clear();
+        track_energy(&[result_without_power]);
+        std::thread::sleep(Duration::from_millis(20));
+        track_energy(&[result]);
+        assert_eq!(joules("energy-test-failed:3493"), 0f64);
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use url::Url;
+
+use crate::common::ErrorResult;
+
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+// For each attempt, so an unresponsive server can't block the caller indefinitely
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// HTTP client for outgoing requests, supporting both HTTP and HTTPS.
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+pub fn build_http_client() -> HttpClient {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new().with_webpki_roots().https_or_http().enable_http1().build();
+    Client::builder().build(connector)
+}
+
+// Remove the credentials from the URL (if any), returning them as a basic auth header value instead.
+pub fn split_url_credentials(url: &Url) -> (Url, Option<String>) {
+    let mut url = url.clone();
+    if url.username().is_empty() {
+        return (url, None);
+    }
+    let credentials = format!("{}:{}", url.username(), url.password().unwrap_or(""));
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    (url, Some(format!("Basic {}", base64::encode(credentials))))
+}
+
+// Send a request, retrying with exponential backoff.
+// The request is built once per attempt since bodies can't be reused.
+pub async fn send_with_retries<F>(client: &HttpClient, build_request: F, retries: u32, description: &str) -> ErrorResult<()>
+where
+    F: Fn() -> hyper::http::Result<Request<Body>>,
+{
+    let mut backoff = INITIAL_RETRY_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        // Keep the error as a string since it's held across the retry backoff
+        let result: Result<(), String> = match build_request() {
+            Ok(request) => match tokio::time::timeout(REQUEST_TIMEOUT, client.request(request)).await {
+                Ok(Ok(response)) if response.status().is_success() => Ok(()),
+                Ok(Ok(response)) => Err(format!("Received status {}", response.status())),
+                Ok(Err(err)) => Err(err.to_string()),
+                Err(_) => Err(format!("Timed out after {} seconds", REQUEST_TIMEOUT.as_secs_f64())),
+            },
+            Err(err) => return Err(format!("{} failed: {}", description, err).into()),
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt >= retries => return Err(format!("{} failed: {}", description, err).into()),
+            Err(err) => {
+                log::warn!("{} failed, retrying in {:?}: {}", description, backoff, err);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            },
+        }
+    }
+}
@@ -2,27 +2,76 @@ use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fmt::Write as _;
 use std::net::{SocketAddr};
+use std::time::{Duration, Instant};
 
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::server::conn::AddrStream;
-use lazy_static::lazy_static;
-use regex::Regex;
+use hyper::header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, HeaderValue, RETRY_AFTER, VARY, WWW_AUTHENTICATE};
+use hyper::server::conn::{AddrStream, Http};
+use serde_json::json;
+use tokio::net::TcpListener;
 use tokio::sync::broadcast::Receiver;
 use url::form_urlencoded;
 
 use crate::meta::{APP_NAME, APP_AUTHOR, APP_VERSION};
 use crate::common::ErrorResult;
+use crate::compression::{compress, negotiate_content_encoding};
 use crate::config::Config;
-use crate::nut_client::scrape_nut;
-use crate::openmetrics_builder::build_openmetrics_content;
+use crate::config_reload::{SharedConfig, current_config, reload_config};
+use crate::labels::build_ups_labels;
+use crate::influx_builder::build_influx_content;
+use crate::json_builder::build_json_content;
+use crate::nut_client::{scrape_nut, scrape_nut_with_extras};
+use crate::openmetrics_builder::{build_metric_families, build_openmetrics_content, build_prometheus_content};
+use crate::poller::build_polled_families;
+use crate::protobuf_builder::build_protobuf_content;
+use crate::rate_limiter::take_rate_limit_token;
+use crate::scrape_cache::scrape_nut_cached;
+use crate::web_config::{TlsServerConfig, build_tls_acceptor, check_basic_auth};
+use crate::service_discovery::build_sd_content;
+use crate::self_metrics::{build_self_metric_families, record_http_request, time_since_successful_scrape};
+use crate::targets::NutTarget;
 
-const CONTENT_TYPE_TEXT: &str = "text/plain; charset=UTF-8";
-const CONTENT_TYPE_OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=UTF-8";
+const API_UPS_PATH: &str = "/api/v1/ups";
+const API_SD_PATH: &str = "/api/v1/sd";
+const SELF_METRICS_PATH: &str = "/metrics";
+const HEALTHY_PATH: &str = "/-/healthy";
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const READY_PATH: &str = "/-/ready";
+const RELOAD_PATH: &str = "/-/reload";
+
+const CONTENT_TYPE_JSON: &str = "application/json";
+const CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+const CONTENT_TYPE_PROMETHEUS_BASE: &str = "text/plain";
+const CONTENT_TYPE_OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
 const CONTENT_TYPE_OPENMETRICS_BASE: &str = "application/openmetrics-text";
+const CONTENT_TYPE_PROTOBUF: &str = "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+const CONTENT_TYPE_PROTOBUF_BASE: &str = "application/vnd.google.protobuf";
+const PROTOBUF_PROTO: &str = "io.prometheus.client.MetricFamily";
+const PROTOBUF_ENCODING: &str = "delimited";
+const CONTENT_TYPE_INFLUX: &str = "application/x-influxdb-line-protocol; charset=utf-8";
+const CONTENT_TYPE_INFLUX_BASE: &str = "application/x-influxdb-line-protocol";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ContentFormat {
+    OpenMetrics,
+    Prometheus,
+    Protobuf,
+    Influx,
+}
 
-pub async fn run_server(config: Config, mut shutdown_channel: Receiver<bool>) {
+// The listener and TLS settings are only read at startup.
+pub async fn run_server(shared_config: SharedConfig, shutdown_channel: Receiver<bool>) {
+    let config = current_config(&shared_config);
+    match &config.web_config.tls_server_config {
+        Some(tls_server_config) => run_tls_server(shared_config.clone(), tls_server_config, shutdown_channel).await,
+        None => run_plain_server(shared_config, shutdown_channel).await,
+    }
+}
+
+async fn run_plain_server(shared_config: SharedConfig, mut shutdown_channel: Receiver<bool>) {
     // Bind to endpoint
+    let config = current_config(&shared_config);
     let endpoint = SocketAddr::new(config.http_address, config.http_port);
     log::info!("Binding to endpoint: http://{}", endpoint);
     let server_builder = match Server::try_bind(&endpoint) {
@@ -37,13 +86,12 @@ pub async fn run_server(config: Config, mut shutdown_channel: Receiver<bool>) {
     let shutdown_future = async {
         shutdown_channel.recv().await.unwrap();
     };
-    let config1 = config.clone();
     let service_maker = make_service_fn(move |conn:  &AddrStream| {
-        let config2 = config1.clone();
+        let shared_config = shared_config.clone();
         let remote_addr = conn.remote_addr();
         async move {
             Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
-                entrypoint(config2.clone(), request, remote_addr)
+                entrypoint(shared_config.clone(), request, remote_addr)
             }))
         }
     });
@@ -55,29 +103,151 @@ pub async fn run_server(config: Config, mut shutdown_channel: Receiver<bool>) {
     }
 }
 
-async fn entrypoint(config: Config, request: Request<Body>, remote_addr: SocketAddr) -> Result<Response<Body>, Infallible> {
+// Serve HTTPS, with the TLS handshake done separately for each connection so slow clients don't block others.
+async fn run_tls_server(shared_config: SharedConfig, tls_server_config: &TlsServerConfig, mut shutdown_channel: Receiver<bool>) {
+    let acceptor = match build_tls_acceptor(tls_server_config) {
+        Ok(acceptor) => acceptor,
+        Err(err) => {
+            log::error!("Failed to set up TLS: {}", err);
+            return;
+        },
+    };
+
+    // Bind to endpoint
+    let config = current_config(&shared_config);
+    let endpoint = SocketAddr::new(config.http_address, config.http_port);
+    log::info!("Binding to endpoint: https://{}", endpoint);
+    let listener = match TcpListener::bind(&endpoint).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Server failed to bind to endpoint: {}", err);
+            return;
+        },
+    };
+
+    // Accept connections until shutdown
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            result = listener.accept() => match result {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::warn!("Failed to accept connection: {}", err);
+                    continue;
+                },
+            },
+            _ = shutdown_channel.recv() => break,
+        };
+        let acceptor = acceptor.clone();
+        let shared_config = shared_config.clone();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    log::debug!("TLS handshake with {} failed: {}", remote_addr, err);
+                    return;
+                },
+                Err(_) => {
+                    log::debug!("TLS handshake with {} timed out.", remote_addr);
+                    return;
+                },
+            };
+            let service = service_fn(move |request: Request<Body>| {
+                entrypoint(shared_config.clone(), request, remote_addr)
+            });
+            if let Err(err) = Http::new().serve_connection(stream, service).await {
+                log::debug!("Connection error for {}: {}", remote_addr, err);
+            }
+        });
+    }
+}
+
+async fn entrypoint(shared_config: SharedConfig, request: Request<Body>, remote_addr: SocketAddr) -> Result<Response<Body>, Infallible> {
     log::trace!("HTTP request from: {}", remote_addr);
     log::trace!("HTTP request URL: {}", request.uri().path());
 
+    let start_time = Instant::now();
+    // Use the same config for the whole request, even if reloaded meanwhile
+    let config = current_config(&shared_config);
     let metrics_path = &config.http_path;
     let is_method_get = request.method() == Method::GET;
     let path = request.uri().path();
+    // Health checks are exempt from rate limiting and auth, for e.g. Kubernetes probes
+    let is_health_check = path == HEALTHY_PATH || path == READY_PATH;
+    let rate_limit_delay = match config.http_rate_limit {
+        Some(http_rate_limit) if !is_health_check => take_rate_limit_token(http_rate_limit).err(),
+        _ => None,
+    };
+    // Known paths only, to limit the cardinality of the self-metrics
+    let path_label: &str;
     let response: Response<Body>;
-    if path == "/" {
+    if let Some(rate_limit_delay) = rate_limit_delay {
+        path_label = "rate_limited";
+        response = endpoint_rate_limited(rate_limit_delay);
+    } else if !is_health_check && !check_basic_auth(&config.web_config.basic_auth_users, request.headers().get(AUTHORIZATION)).await {
+        path_label = "unauthorized";
+        response = endpoint_unauthorized();
+    } else if path == "/" {
+        path_label = "/";
         if is_method_get {
             response = endpoint_home(&config);
         } else {
             response = endpoint_method_not_allowed();
         }
+    } else if is_self_metrics_request(&config, &request) {
+        path_label = SELF_METRICS_PATH;
+        if is_method_get {
+            response = endpoint_self_metrics(&request);
+        } else {
+            response = endpoint_method_not_allowed();
+        }
     } else if path == metrics_path {
+        path_label = metrics_path;
         if is_method_get {
             response = endpoint_metrics(&config, &request).await;
         } else {
             response = endpoint_method_not_allowed();
         }
+    } else if path == HEALTHY_PATH {
+        path_label = HEALTHY_PATH;
+        if is_method_get {
+            response = endpoint_healthy();
+        } else {
+            response = endpoint_method_not_allowed();
+        }
+    } else if path == READY_PATH {
+        path_label = READY_PATH;
+        if is_method_get {
+            response = endpoint_ready(&config);
+        } else {
+            response = endpoint_method_not_allowed();
+        }
+    } else if path == RELOAD_PATH {
+        path_label = RELOAD_PATH;
+        if request.method() == Method::POST {
+            response = endpoint_reload(&shared_config).await;
+        } else {
+            response = endpoint_method_not_allowed();
+        }
+    } else if path == API_UPS_PATH {
+        path_label = API_UPS_PATH;
+        if is_method_get {
+            response = endpoint_api_ups(&config, &request).await;
+        } else {
+            response = endpoint_method_not_allowed();
+        }
+    } else if path == API_SD_PATH {
+        path_label = API_SD_PATH;
+        if is_method_get {
+            response = endpoint_api_sd(&config, &request).await;
+        } else {
+            response = endpoint_method_not_allowed();
+        }
     } else {
+        path_label = "other";
         response = endpoint_not_found();
     }
+    let response = compress_response(&config, &request, response).await;
+    record_http_request(path_label, response.status().as_u16(), start_time.elapsed());
 
     // Log request to console
     log::debug!("Request: {} {} {} {}", remote_addr, request.method(), request.uri().path(), response.status().to_string());
@@ -85,11 +255,57 @@ async fn entrypoint(config: Config, request: Request<Body>, remote_addr: SocketA
     Ok(response)
 }
 
+// Compress the response if enabled, not too small and the client accepts a supported encoding.
+async fn compress_response(config: &Config, request: &Request<Body>, response: Response<Body>) -> Response<Body> {
+    if !config.http_compression || response.headers().contains_key(CONTENT_ENCODING) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    // The response may differ by encoding even if not compressed this time, so caches must not mix them
+    parts.headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    let encoding = match request.headers().get(ACCEPT_ENCODING).map(|header| header.to_str()) {
+        Some(Ok(accept_encoding_str)) => negotiate_content_encoding(accept_encoding_str),
+        _ => None,
+    };
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return Response::from_parts(parts, body),
+    };
+
+    // All bodies are already fully built
+    let content = match hyper::body::to_bytes(body).await {
+        Ok(content) => content,
+        Err(err) => {
+            log::error!("Failed to read response body for compression: {}", err);
+            return Response::from_parts(parts, Body::empty());
+        },
+    };
+    if content.len() < config.http_compression_min_size {
+        return Response::from_parts(parts, Body::from(content));
+    }
+    match compress(encoding, &content) {
+        Ok(compressed_content) => {
+            parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(compressed_content))
+        },
+        Err(err) => {
+            log::error!("Failed to compress response with {}: {}", encoding.name(), err);
+            Response::from_parts(parts, Body::from(content))
+        },
+    }
+}
+
 fn endpoint_home(config: &Config) -> Response<Body> {
     let mut content = String::new();
     let _ = writeln!(content, "{} version {} by {}.", APP_NAME, APP_VERSION, APP_AUTHOR);
     let _ = writeln!(content);
-    let _ = writeln!(content, "Usage: {}?target=<target>", config.http_path);
+    let _ = writeln!(content, "Usage: {}?target=<target>[&ups=<ups>][&format=<openmetrics|prometheus|protobuf|influx>][&nocache=true]", config.http_path);
+    let _ = writeln!(content, "Raw NUT vars as JSON: {}?target=<target>[&rw=true][&cmd=true]", API_UPS_PATH);
+    let _ = writeln!(content, "Prometheus service discovery: {}[?ups=true]", API_SD_PATH);
+    let _ = writeln!(content, "Exporter metrics: {}", SELF_METRICS_PATH);
+    let _ = writeln!(content, "Health and readiness: {} and {}", HEALTHY_PATH, READY_PATH);
+    let _ = writeln!(content, "Reload config: POST {}", RELOAD_PATH);
 
     Response::builder().status(StatusCode::OK).body(Body::from(content)).unwrap()
 }
@@ -98,64 +314,286 @@ fn endpoint_not_found() -> Response<Body> {
     Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Not found\n")).unwrap()
 }
 
+fn endpoint_unauthorized() -> Response<Body> {
+    Response::builder().status(StatusCode::UNAUTHORIZED).header(WWW_AUTHENTICATE, format!("Basic realm=\"{}\"", APP_NAME)).body(Body::from("Unauthorized\n")).unwrap()
+}
+
+fn endpoint_rate_limited(delay: Duration) -> Response<Body> {
+    let retry_after = delay.as_secs_f64().ceil().max(1f64);
+    Response::builder().status(StatusCode::TOO_MANY_REQUESTS).header(RETRY_AFTER, retry_after.to_string()).body(Body::from("Too many requests\n")).unwrap()
+}
+
+fn endpoint_target_forbidden(target: &NutTarget) -> Response<Body> {
+    log::warn!("Rejected request for target not in the allowlist: {}", target.name);
+    Response::builder().status(StatusCode::FORBIDDEN).body(Body::from(format!("Target not allowed: {}\n", target.name))).unwrap()
+}
+
 fn endpoint_method_not_allowed() -> Response<Body> {
     Response::builder().status(StatusCode::METHOD_NOT_ALLOWED).body(Body::from("Method not allowed\n")).unwrap()
 }
 
 async fn endpoint_metrics(config: &Config, request: &Request<Body>) -> Response<Body> {
     // Check for and parse target
-    let usage_message = format!("Usage: {}?target=<target>", config.http_path);
-    let target = match parse_target(request) {
+    let usage_message = format!("Usage: {}?target=<target>[&ups=<ups>][&format=<openmetrics|prometheus|protobuf|influx>][&nocache=true]", config.http_path);
+    let target = match parse_target(config, request) {
         Ok(target) => target,
         Err(err) => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from(format!("{}\n\n{}", err, usage_message))).unwrap(),
     };
+    if !is_target_allowed(config, &target) {
+        return endpoint_target_forbidden(&target);
+    }
+    let content_format = match select_content_format(request) {
+        Ok(content_format) => content_format,
+        Err(err) => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from(format!("{}\n\n{}", err, usage_message))).unwrap(),
+    };
 
-    // Try to scrape NUT server
-    let (upses, nut_version) = match scrape_nut(&target).await {
+    // Try to scrape NUT server, or use the cached result (if enabled and not bypassed)
+    let query_args = parse_query_args(request);
+    let scrape_result = match config.scrape_cache_ttl {
+        Some(scrape_cache_ttl) => scrape_nut_cached(&target, scrape_cache_ttl, parse_query_bool(query_args.get("nocache"))).await,
+        None => scrape_nut(&target).await,
+    };
+    let (mut upses, nut_version) = match scrape_result {
         Ok(x) =>  x,
         Err(err) => return Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::from(err.to_string())).unwrap(),
     };
+    // Only include the selected UPS, e.g. for per-UPS service discovery
+    if let Some(ups) = query_args.get("ups") {
+        upses.retain(|name, _| name == ups);
+    }
 
-    // Generate OpenMetrics output
-    let content = build_openmetrics_content(&upses, &nut_version);
+    // Generate output in the selected format
+    let ups_labels = build_ups_labels(config, &target.name, &upses);
+    let mut families = build_metric_families(&upses, &nut_version, &ups_labels);
+    families.append(&mut build_polled_families(&target.name, &ups_labels));
+    let (content, content_type) = match content_format {
+        ContentFormat::OpenMetrics => (build_openmetrics_content(&families).into_bytes(), CONTENT_TYPE_OPENMETRICS),
+        ContentFormat::Prometheus => (build_prometheus_content(&families).into_bytes(), CONTENT_TYPE_PROMETHEUS),
+        ContentFormat::Protobuf => (build_protobuf_content(&families), CONTENT_TYPE_PROTOBUF),
+        ContentFormat::Influx => (build_influx_content(&target.name, &families).into_bytes(), CONTENT_TYPE_INFLUX),
+    };
 
-    // Set content type
-    let mut content_type = CONTENT_TYPE_TEXT;
-    if let Some(accept_header) = request.headers().get("accept") {
-        if let Ok(accept_str) = accept_header.to_str() {
-            if accept_str.contains(CONTENT_TYPE_OPENMETRICS_BASE) {
-                content_type = CONTENT_TYPE_OPENMETRICS;
-            }
+    Response::builder().status(StatusCode::OK).header("Content-Type", content_type).body(Body::from(content)).unwrap()
+}
+
+// The process is alive (and serving requests).
+fn endpoint_healthy() -> Response<Body> {
+    let content = json!({ "status": "healthy" }).to_string();
+    Response::builder().status(StatusCode::OK).header("Content-Type", CONTENT_TYPE_JSON).body(Body::from(content)).unwrap()
+}
+
+// The listener is up and the config is loaded (else the server wouldn't run),
+// and optionally some target (a named or poll target if any, else a known target) was scraped successfully recently.
+fn endpoint_ready(config: &Config) -> Response<Body> {
+    if let Some(max_scrape_age) = config.ready_max_scrape_age {
+        let targets: Vec<String> = config.targets.keys().chain(config.poll_targets.iter()).cloned().collect();
+        let is_recent = matches!(time_since_successful_scrape(&targets), Some(scrape_age) if scrape_age <= max_scrape_age);
+        if !is_recent {
+            let reason = format!("No successful scrape within the last {} seconds.", max_scrape_age.as_secs_f64());
+            let content = json!({ "status": "not_ready", "reason": reason }).to_string();
+            return Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).header("Content-Type", CONTENT_TYPE_JSON).body(Body::from(content)).unwrap();
         }
     }
 
+    let content = json!({ "status": "ready" }).to_string();
+    Response::builder().status(StatusCode::OK).header("Content-Type", CONTENT_TYPE_JSON).body(Body::from(content)).unwrap()
+}
+
+async fn endpoint_reload(shared_config: &SharedConfig) -> Response<Body> {
+    // Reading the config files is blocking
+    let shared_config = shared_config.clone();
+    let result = tokio::task::spawn_blocking(move || reload_config(&shared_config).map_err(|err| err.to_string())).await
+        .unwrap_or_else(|err| Err(err.to_string()));
+    match result {
+        Ok(()) => Response::builder().status(StatusCode::OK).body(Body::from("Config reloaded\n")).unwrap(),
+        Err(err) => Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(format!("Failed to reload config: {}\n", err))).unwrap(),
+    }
+}
+
+fn endpoint_self_metrics(request: &Request<Body>) -> Response<Body> {
+    let content_format = match select_content_format(request) {
+        Ok(content_format) => content_format,
+        Err(err) => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from(format!("{}\n", err))).unwrap(),
+    };
+
+    let families = build_self_metric_families();
+    let (content, content_type) = match content_format {
+        ContentFormat::OpenMetrics => (build_openmetrics_content(&families).into_bytes(), CONTENT_TYPE_OPENMETRICS),
+        ContentFormat::Prometheus => (build_prometheus_content(&families).into_bytes(), CONTENT_TYPE_PROMETHEUS),
+        ContentFormat::Protobuf => (build_protobuf_content(&families), CONTENT_TYPE_PROTOBUF),
+        ContentFormat::Influx => (build_influx_content("", &families).into_bytes(), CONTENT_TYPE_INFLUX),
+    };
+
     Response::builder().status(StatusCode::OK).header("Content-Type", content_type).body(Body::from(content)).unwrap()
 }
 
-fn parse_target(request: &Request<Body>) -> ErrorResult<String> {
-    lazy_static! {
-        // Match domain, IPv4 address or IPv6 addres, with optional port number
-        static ref TARGET_PATTERN: Regex = Regex::new(r#"^(?P<host>\[[^\]]+\]|[^:]+)(?::(?P<port>[0-9]+))?$"#).unwrap();
+async fn endpoint_api_ups(config: &Config, request: &Request<Body>) -> Response<Body> {
+    // Check for and parse target and options
+    let usage_message = format!("Usage: {}?target=<target>[&rw=true][&cmd=true]", API_UPS_PATH);
+    let target = match parse_target(config, request) {
+        Ok(target) => target,
+        Err(err) => return Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from(format!("{}\n\n{}", err, usage_message))).unwrap(),
+    };
+    if !is_target_allowed(config, &target) {
+        return endpoint_target_forbidden(&target);
+    }
+    let query_args = parse_query_args(request);
+    let include_rws = parse_query_bool(query_args.get("rw"));
+    let include_cmds = parse_query_bool(query_args.get("cmd"));
+
+    // Try to scrape NUT server
+    let (upses, nut_version, extras) = match scrape_nut_with_extras(&target, include_rws, include_cmds).await {
+        Ok(x) =>  x,
+        Err(err) => return Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::from(err.to_string())).unwrap(),
+    };
+
+    let content = build_json_content(&target.name, &upses, &nut_version, &extras);
+    Response::builder().status(StatusCode::OK).header("Content-Type", CONTENT_TYPE_JSON).body(Body::from(content)).unwrap()
+}
+
+async fn endpoint_api_sd(config: &Config, request: &Request<Body>) -> Response<Body> {
+    let include_upses = parse_query_bool(parse_query_args(request).get("ups"));
+    let content = build_sd_content(config, include_upses).await;
+    Response::builder().status(StatusCode::OK).header("Content-Type", CONTENT_TYPE_JSON).body(Body::from(content)).unwrap()
+}
+
+// If the metrics path is also "/metrics", requests without a target are for the self-metrics.
+fn is_self_metrics_request(config: &Config, request: &Request<Body>) -> bool {
+    if request.uri().path() != SELF_METRICS_PATH {
+        return false;
+    }
+    config.http_path != SELF_METRICS_PATH || !parse_query_args(request).contains_key("target")
+}
+
+// Use the format from the "format" query arg if present, else use content negotiation.
+fn select_content_format(request: &Request<Body>) -> ErrorResult<ContentFormat> {
+    match parse_query_args(request).get("format").map(String::as_str) {
+        Some("openmetrics") => Ok(ContentFormat::OpenMetrics),
+        Some("prometheus") => Ok(ContentFormat::Prometheus),
+        Some("protobuf") => Ok(ContentFormat::Protobuf),
+        Some("influx") => Ok(ContentFormat::Influx),
+        Some(format) => Err(format!("Unknown format: {}", format).into()),
+        None => Ok(negotiate_content_format(request)),
     }
+}
+
+// Pick the supported format with the highest quality in the Accept header, defaulting to the Prometheus text format.
+fn negotiate_content_format(request: &Request<Body>) -> ContentFormat {
+    let accept_str = match request.headers().get("accept").map(|header| header.to_str()) {
+        Some(Ok(accept_str)) => accept_str,
+        _ => return ContentFormat::Prometheus,
+    };
+
+    let mut best_format = ContentFormat::Prometheus;
+    let mut best_quality = 0f32;
+    for media_range in accept_str.split(',') {
+        let mut parts = media_range.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or("").to_lowercase();
+        let params: HashMap<String, &str> = parts
+            .filter_map(|param| param.split_once('='))
+            .map(|(key, value)| (key.trim().to_lowercase(), value.trim().trim_matches('"')))
+            .collect();
+        let format = match media_type.as_str() {
+            CONTENT_TYPE_OPENMETRICS_BASE => ContentFormat::OpenMetrics,
+            CONTENT_TYPE_PROMETHEUS_BASE => ContentFormat::Prometheus,
+            CONTENT_TYPE_INFLUX_BASE => ContentFormat::Influx,
+            CONTENT_TYPE_PROTOBUF_BASE if params.get("proto") == Some(&PROTOBUF_PROTO) && params.get("encoding") == Some(&PROTOBUF_ENCODING) => ContentFormat::Protobuf,
+            _ => continue,
+        };
+        let quality = params.get("q").map_or(1f32, |value| value.parse::<f32>().unwrap_or(0f32));
+        if quality > best_quality {
+            best_format = format;
+            best_quality = quality;
+        }
+    }
+
+    best_format
+}
 
-    let query_args: HashMap<String, String> = form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes()).into_owned().collect();
+// Look up the target as a named target first, else parse it as an address (which must be allowed separately).
+fn parse_target(config: &Config, request: &Request<Body>) -> ErrorResult<NutTarget> {
+    let query_args = parse_query_args(request);
     let target_raw = match query_args.get("target") {
         Some(target_raw) => target_raw,
         None => return Err("Missing target.".into()),
     };
 
-    let default_port = Config::DEFAULT_NUT_PORT.to_string();
-    let target = match TARGET_PATTERN.captures(target_raw) {
-        Some(captures) => {
-            let host = captures.name("host").unwrap().as_str();
-            let port = match captures.name("port") {
-                Some(port) => port.as_str(),
-                None => default_port.as_str(),
-            };
-            format!("{}:{}", host, port)
-        },
-        None => return Err("Malformed list element for VAR list query.".into()),
-    };
+    config.resolve_target(target_raw)
+}
 
-    Ok(target)
+// Targets configured in the exporter (named and poll targets) are always allowed,
+// other targets only if there's no allowlist or they're in it.
+fn is_target_allowed(config: &Config, target: &NutTarget) -> bool {
+    if config.targets.contains_key(&target.name) || config.poll_targets.contains(&target.name) {
+        return true;
+    }
+    match &config.target_allowlist {
+        Some(target_allowlist) => target_allowlist.is_allowed(&target.address),
+        None => true,
+    }
+}
+
+fn parse_query_args(request: &Request<Body>) -> HashMap<String, String> {
+    form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes()).into_owned().collect()
+}
+
+// Missing values are false
+fn parse_query_bool(value: Option<&String>) -> bool {
+    matches!(value.map(String::as_str), Some("1" | "true" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::StatusCode;
+
+    use crate::config::read_config;
+    use crate::self_metrics::record_scrape;
+    use crate::targets::NutTarget;
+
+    use super::endpoint_ready;
+
+    fn build_known_target(address: &str) -> NutTarget {
+        let mut target = NutTarget::from_address(address.to_owned(), Duration::from_secs(10), 2);
+        target.is_known = true;
+        target
+    }
+
+    #[test]
+    fn ready_without_max_scrape_age() {
+        let config = read_config();
+        assert_eq!(config.ready_max_scrape_age, None);
+        assert_eq!(endpoint_ready(&config).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn ready_requires_recent_scrape_of_configured_target() {
+        let mut config = read_config();
+        config.ready_max_scrape_age = Some(Duration::from_secs(60));
+        config.poll_targets = vec!["ready-test-poll:3493".to_owned()];
+        assert_eq!(endpoint_ready(&config).status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Scrapes of other targets and failed scrapes don't count
+        record_scrape(&build_known_target("ready-test-other:3493"), Duration::from_millis(3), false);
+        record_scrape(&NutTarget::from_address("ready-test-unknown:3493".to_owned(), Duration::from_secs(10), 2), Duration::from_millis(3), false);
+        record_scrape(&build_known_target("ready-test-poll:3493"), Duration::from_millis(3), true);
+        assert_eq!(endpoint_ready(&config).status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        record_scrape(&build_known_target("ready-test-poll:3493"), Duration::from_millis(3), false);
+        assert_eq!(endpoint_ready(&config).status(), StatusCode::OK);
+
+        // A scrape too long ago
+        config.ready_max_scrape_age = Some(Duration::from_nanos(1));
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(endpoint_ready(&config).status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn ready_requires_recent_scrape_of_known_target() {
+        let mut config = read_config();
+        config.ready_max_scrape_age = Some(Duration::from_secs(60));
+        record_scrape(&build_known_target("ready-test-known:3493"), Duration::from_millis(3), false);
+        assert_eq!(endpoint_ready(&config).status(), StatusCode::OK);
+    }
 }
@@ -0,0 +1,124 @@
+use std::fmt::Write as _;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::openmetrics_builder::{MetricFamilies, name_samples};
+
+// Build the InfluxDB line protocol format, with one measurement per metric family and all labels as tags.
+// All points use the same timestamp, for the time of the scrape.
+pub fn build_influx_content(target: &str, families: &MetricFamilies) -> String {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_nanos());
+
+    let mut builder: String = String::new();
+    for (metric, samples) in families.iter() {
+        for sample in samples.iter().flat_map(|sample| name_samples(metric, sample)) {
+            // Not supported by InfluxDB
+            if !sample.value.is_finite() {
+                continue;
+            }
+
+            let mut tags: Vec<(&str, &str)> = vec![("server", target)];
+            tags.extend(sample.labels.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+            // Empty tag values are not allowed and sorted tags are faster to ingest
+            tags.retain(|(_, value)| !value.is_empty());
+            tags.sort_unstable();
+
+            let _ = write!(builder, "{}", escape_influx(&sample.name, false));
+            for (name, value) in tags.iter() {
+                let _ = write!(builder, ",{}={}", escape_influx(name, true), escape_influx(value, true));
+            }
+            let _ = match sample.is_integer {
+                true => writeln!(builder, " value={:.0}i {}", sample.value, timestamp),
+                false => writeln!(builder, " value={} {}", sample.value, timestamp),
+            };
+        }
+    }
+
+    builder
+}
+
+// Escape measurements (backslashes, commas and spaces) or tag keys and values (also equal signs).
+fn escape_influx(raw_text: &str, is_tag: bool) -> String {
+    raw_text.chars().map(|c| match c {
+        '\\' => r#"\\"#.to_string(),
+        ',' => r#"\,"#.to_string(),
+        ' ' => r#"\ "#.to_string(),
+        '=' if is_tag => r#"\="#.to_string(),
+        '\n' => r#"\n"#.to_string(),
+        _ => c.to_string(),
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::labels::UpsLabelMap;
+    use crate::metrics::{HTTP_REQUEST_DURATION_METRIC, SCRAPE_DURATION_METRIC, UpsVarMap, VarMap};
+    use crate::openmetrics_builder::{MetricFamilies, Sample, build_metric_families};
+
+    use super::{build_influx_content, escape_influx};
+
+    #[test]
+    fn special_characters_are_escaped() {
+        assert_eq!(escape_influx(r#"a\b,c d=e"#, false), r#"a\\b\,c\ d=e"#);
+        assert_eq!(escape_influx(r#"a\b,c d=e"#, true), r#"a\\b\,c\ d\=e"#);
+        // A trailing backslash must not escape the following separator
+        assert_eq!(escape_influx(r#"a\"#, true), r#"a\\"#);
+        assert_eq!(escape_influx("a\nb", true), r#"a\nb"#);
+    }
+
+    #[test]
+    fn influx_output_has_expected_lines() {
+        let vars: VarMap = HashMap::from([
+            ("battery.charge".to_owned(), "50".to_owned()),
+            ("battery.runtime".to_owned(), "1200".to_owned()),
+        ]);
+        let upses: UpsVarMap = HashMap::from([("ups 1".to_owned(), vars)]);
+        let ups_labels: UpsLabelMap = HashMap::from([("ups 1".to_owned(), vec![("rack".to_owned(), "a,b=c\\".to_owned()), ("room".to_owned(), String::new())])]);
+        let content = build_influx_content("127.0.0.1:3493", &build_metric_families(&upses, "2.8.0", &ups_labels));
+
+        let mut timestamps = Vec::new();
+        let lines: Vec<&str> = content.lines().map(|line| {
+            let (point, timestamp) = line.rsplit_once(' ').unwrap();
+            timestamps.push(timestamp.parse::<u128>().unwrap());
+            point
+        }).collect();
+        // Tags are sorted, empty tags are left out and integer metrics have integer values
+        for expected_line in [
+            r#"nut_ups_info,rack=a\,b\=c\\,server=127.0.0.1:3493,ups=ups\ 1 value=1i"#,
+            r#"nut_battery_charge,rack=a\,b\=c\\,server=127.0.0.1:3493,ups=ups\ 1 value=0.5"#,
+            r#"nut_battery_runtime_seconds,rack=a\,b\=c\\,server=127.0.0.1:3493,ups=ups\ 1 value=1200i"#,
+        ] {
+            assert!(lines.contains(&expected_line), "Missing line: {}\n\n{}", expected_line, content);
+        }
+        assert!(timestamps.iter().all(|timestamp| *timestamp > 0 && *timestamp == timestamps[0]));
+    }
+    #[test]
+    fn histograms_are_split_into_samples() {
+        let build_labels = |labels: &[(&str, &str)]| -> Vec<(String, String)> {
+            labels.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+        };
+        let families: MetricFamilies = vec![
+            (&SCRAPE_DURATION_METRIC, vec![
+                Sample { labels: build_labels(&[("target", "nut")]), value: 0.25 },
+                Sample { labels: build_labels(&[("target", "other")]), value: f64::NAN },
+            ]),
+            (&HTTP_REQUEST_DURATION_METRIC, vec![
+                Sample { labels: build_labels(&[("path", "/metrics"), ("le", "0.1")]), value: 2.0 },
+                Sample { labels: build_labels(&[("path", "/metrics"), ("le", "+Inf")]), value: 3.0 },
+                Sample { labels: build_labels(&[("path", "/metrics")]), value: 0.5 },
+            ]),
+        ];
+        let content = build_influx_content("127.0.0.1:3493", &families);
+
+        let lines: Vec<&str> = content.lines().map(|line| line.rsplit_once(' ').unwrap().0).collect();
+        // NaN values are left out
+        assert_eq!(lines, vec![
+            "nut_exporter_scrape_duration_seconds_total,server=127.0.0.1:3493,target=nut value=0.25",
+            "nut_exporter_http_request_duration_seconds_bucket,le=0.1,path=/metrics,server=127.0.0.1:3493 value=2i",
+            "nut_exporter_http_request_duration_seconds_bucket,le=+Inf,path=/metrics,server=127.0.0.1:3493 value=3i",
+            "nut_exporter_http_request_duration_seconds_count,path=/metrics,server=127.0.0.1:3493 value=3i",
+            "nut_exporter_http_request_duration_seconds_sum,path=/metrics,server=127.0.0.1:3493 value=0.5",
+        ]);
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::metrics::{UPS_DESCRIPTION_PSEUDOVAR, UpsVarMap};
+use crate::nut_client::NutExtras;
+
+// Sorted maps are used for stable output
+#[derive(Debug, Serialize)]
+struct ApiUpsResponse<'a> {
+    target: &'a str,
+    nut_version: &'a str,
+    upses: BTreeMap<&'a str, ApiUps<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ApiUps<'a> {
+    description: Option<&'a str>,
+    vars: BTreeMap<&'a str, &'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rw_vars: Option<BTreeMap<&'a str, &'a str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commands: Option<Vec<&'a str>>,
+}
+
+// Build JSON with the raw NUT vars for all UPSes, with the description pseudo-var as a separate field.
+pub fn build_json_content(target: &str, upses: &UpsVarMap, nut_version: &str, extras: &NutExtras) -> String {
+    let mut response = ApiUpsResponse {
+        target,
+        nut_version,
+        upses: BTreeMap::new(),
+    };
+
+    for (ups, vars) in upses.iter() {
+        let api_ups = ApiUps {
+            description: vars.get(UPS_DESCRIPTION_PSEUDOVAR).map(String::as_str),
+            vars: vars.iter().filter(|(var, _)| *var != UPS_DESCRIPTION_PSEUDOVAR).map(|(var, val)| (var.as_str(), val.as_str())).collect(),
+            rw_vars: extras.rws.as_ref().map(|rws| {
+                rws.get(ups).map(|rw_vars| rw_vars.iter().map(|(var, val)| (var.as_str(), val.as_str())).collect()).unwrap_or_default()
+            }),
+            commands: extras.cmds.as_ref().map(|cmds| {
+                cmds.get(ups).map(|ups_cmds| ups_cmds.iter().map(String::as_str).collect()).unwrap_or_default()
+            }),
+        };
+        response.upses.insert(ups, api_ups);
+    }
+
+    // Serializing plain maps and strings can't fail
+    let mut content = serde_json::to_string_pretty(&response).unwrap();
+    content.push('\n');
+
+    content
+}
@@ -0,0 +1,48 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::Config;
+use crate::metrics::UpsVarMap;
+
+pub type LabelList = Vec<(String, String)>;
+pub type UpsLabelMap = HashMap<String, LabelList>;
+
+// Find the extra labels to add to all metrics for each UPS.
+// Static labels are applied first (see build_static_labels), then labels from NUT vars, so later ones take precedence.
+pub fn build_ups_labels(config: &Config, target: &str, upses: &UpsVarMap) -> UpsLabelMap {
+    let mut ups_labels: UpsLabelMap = HashMap::new();
+
+    for (ups, vars) in upses.iter() {
+        let mut labels = build_static_labels(config, target, Some(ups));
+        for (label, var) in config.var_labels.iter() {
+            if let Some(value) = vars.get(var) {
+                labels.insert(label.clone(), value.clone());
+            }
+        }
+        ups_labels.insert(ups.clone(), labels.into_iter().collect());
+    }
+
+    ups_labels
+}
+
+// Find the labels from the config for a target and optionally a UPS, without needing the NUT vars.
+// Labels from matching rules are applied in order, then labels from the named target, so later ones take precedence.
+// Without a UPS, only rules without a UPS regex match.
+pub fn build_static_labels(config: &Config, target: &str, ups: Option<&str>) -> BTreeMap<String, String> {
+    let mut labels: BTreeMap<String, String> = BTreeMap::new();
+    for rule in config.label_rules.iter() {
+        let target_matches = rule.target.as_ref().map(|regex| regex.is_match(target)).unwrap_or(true);
+        let ups_matches = match (&rule.ups, ups) {
+            (Some(regex), Some(ups)) => regex.is_match(ups),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        if target_matches && ups_matches {
+            labels.extend(rule.labels.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+    }
+    if let Some(named_target) = config.targets.get(target) {
+        labels.extend(named_target.labels.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    labels
+}
@@ -1,10 +1,32 @@
 mod common;
+mod compression;
 mod config;
+mod config_reload;
+mod energy_tracker;
+mod http_client;
 mod http_server;
+mod influx_builder;
+mod json_builder;
+mod labels;
 mod meta;
 mod metrics;
+mod mqtt_client;
 mod nut_client;
 mod openmetrics_builder;
+mod poller;
+mod protobuf_builder;
+mod rate_limiter;
+mod push_client;
+mod scrape_cache;
+mod self_metrics;
+mod service_discovery;
+mod status_tracker;
+mod target_allowlist;
+mod targets;
+mod web_config;
+mod webhook_client;
+
+use std::sync::{Arc, RwLock};
 
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::broadcast;
@@ -15,33 +37,54 @@ async fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(config::Config::DEFAULT_LOG_LEVEL)).init();
 
     // Setup config
-    let config = config::read_config();
+    let mut config = config::read_config();
     if config.print_metrics_and_exit {
         metrics::print_metrics();
         return;
     }
+    if let Err(err) = config_reload::read_config_files(&mut config) {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
+    let shared_config = Arc::new(RwLock::new(Arc::new(config)));
 
     // Start server
     let (shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
-    let server_task = tokio::spawn(http_server::run_server(config, shutdown_tx.subscribe()));
+    let poller_task = tokio::spawn(poller::run_poller(shared_config.clone(), shutdown_tx.subscribe()));
+    let sd_file_writer_task = tokio::spawn(service_discovery::run_sd_file_writer(shared_config.clone(), shutdown_tx.subscribe()));
+    let server_task = tokio::spawn(http_server::run_server(shared_config.clone(), shutdown_tx.subscribe()));
 
-    // Listen for shutdown signals
+    // Listen for shutdown and reload signals
     let mut sigint_stream = signal(SignalKind::interrupt()).unwrap();
     let mut sigterm_stream = signal(SignalKind::terminate()).unwrap();
-    tokio::select! {
-        _ = shutdown_rx.recv() => {
-            log::debug!("Received internal shutdown signal.");
-        },
-        _ = sigint_stream.recv() => {
-            log::debug!("Received interrupt signal.");
-            shutdown_tx.send(true).unwrap();
-        },
-        _ = sigterm_stream.recv() => {
-            log::debug!("Received termination signal.");
-            shutdown_tx.send(true).unwrap();
-        },
+    let mut sighup_stream = signal(SignalKind::hangup()).unwrap();
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.recv() => {
+                log::debug!("Received internal shutdown signal.");
+                break;
+            },
+            _ = sigint_stream.recv() => {
+                log::debug!("Received interrupt signal.");
+                shutdown_tx.send(true).unwrap();
+                break;
+            },
+            _ = sigterm_stream.recv() => {
+                log::debug!("Received termination signal.");
+                shutdown_tx.send(true).unwrap();
+                break;
+            },
+            _ = sighup_stream.recv() => {
+                log::debug!("Received hangup signal.");
+                // Errors are logged and exposed as a metric
+                let shared_config = shared_config.clone();
+                let _ = tokio::task::spawn_blocking(move || { let _ = config_reload::reload_config(&shared_config); }).await;
+            },
+        }
     }
 
-    // Wait for server
+    // Wait for server, poller and service discovery file writer
     server_task.await.unwrap();
+    poller_task.await.unwrap();
+    sd_file_writer_task.await.unwrap();
 }
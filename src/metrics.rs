@@ -8,12 +8,20 @@ pub type NutVersion = String;
 
 pub const UPS_DESCRIPTION_PSEUDOVAR: &str = "_description";
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum VarTransform {
     None,
     Percentage,
-    BeeperStatus,
+    // Map string values to numbers, using the default for unknown values
+    Map { values: &'static [(&'static str, f64)], default: f64 },
+    // Map string values to the index of a state set state by their (case-insensitive) prefixes, using the last state for unknown values.
+    // Each state is a sample with the state in the label.
+    StateMap { label: &'static str, states: &'static [(&'static str, &'static [&'static str])] },
+    // Linear conversion of numeric values (value * scale + offset), e.g. for unit conversions
+    Linear { scale: f64, offset: f64 },
     OldUpsStatus,
+    Timestamp,
+    Age,
 }
 
 #[derive(Debug, Clone)]
@@ -27,6 +35,15 @@ pub struct Metric {
     pub is_integer: bool,
 }
 
+// Linear conversions for vars in non-SI units
+pub const MINUTES_TO_SECONDS: VarTransform = VarTransform::Linear { scale: 60.0, offset: 0.0 };
+
+pub const BEEPER_STATUS_VALUES: [(&str, f64); 3] = [
+    ("enabled", 1.0),
+    ("disabled", 2.0),
+    ("muted", 3.0),
+];
+
 pub const UPS_STATUS_ELEMENTS: [&str; 11] = [
     "OL",       // online
     "OB",       // on battery
@@ -41,6 +58,17 @@ pub const UPS_STATUS_ELEMENTS: [&str; 11] = [
     "OFF",      // off
 ];
 
+// Self-test results, with the prefixes of the free-text results drivers report
+pub const UPS_TEST_RESULT_STATES: [(&str, &[&str]); 7] = [
+    ("passed", &["done and passed", "passed", "ok"]),
+    ("warning", &["done and warning", "warning"]),
+    ("failed", &["done and error", "error", "failed", "test failed"]),
+    ("aborted", &["aborted"]),
+    ("in_progress", &["in progress"]),
+    ("none", &["no test initiated"]),
+    ("unknown", &[]),
+];
+
 // Special metrics
 pub const EXPORTER_INFO_METRIC: Metric = Metric {
     metric: "nut_exporter_info",
@@ -78,6 +106,180 @@ pub const UPS_STATUS_METRIC: Metric = Metric {
     var_transform: VarTransform::None,
     is_integer: true,
 };
+pub const UPS_TEST_RESULT_METRIC: Metric = Metric {
+    metric: "nut_ups_test_result",
+    help: "Result of the last UPS self-test. Check for a specific result with the \"result\" label.",
+    type_: "stateset",
+    unit: "",
+    nut_var: "ups.test.result",
+    var_transform: VarTransform::StateMap { label: "result", states: &UPS_TEST_RESULT_STATES },
+    is_integer: true,
+};
+// Derived metrics, for UPSes which don't report the power
+pub const ESTIMATED_REAL_POWER_METRIC: Metric = Metric {
+    metric: "nut_real_power_estimated_watts",
+    help: "Real power, estimated from the load and the nominal real power. Only present if the real power is not reported.",
+    type_: "gauge",
+    unit: "watts",
+    nut_var: "ups.load",
+    var_transform: VarTransform::None,
+    is_integer: false,
+};
+pub const ESTIMATED_POWER_METRIC: Metric = Metric {
+    metric: "nut_power_estimated_watts",
+    help: "Apparent power, estimated from the load and the nominal apparent power. Only present if the apparent power is not reported.",
+    type_: "gauge",
+    unit: "watts",
+    nut_var: "ups.load",
+    var_transform: VarTransform::None,
+    is_integer: false,
+};
+// Polling mode metrics, tracked across polls
+pub const UPS_STATUS_TRANSITIONS_METRIC: Metric = Metric {
+    metric: "nut_ups_status_transitions_total",
+    help: "Number of times each UPS status has been set, in polling mode. Check for a specific status with the \"status\" label.",
+    type_: "counter",
+    unit: "",
+    nut_var: "ups.status",
+    var_transform: VarTransform::None,
+    is_integer: true,
+};
+pub const UPS_STATUS_SECONDS_METRIC: Metric = Metric {
+    metric: "nut_ups_status_seconds_total",
+    help: "Cumulative time each UPS status has been set, in polling mode. Check for a specific status with the \"status\" label.",
+    type_: "counter",
+    unit: "seconds",
+    nut_var: "ups.status",
+    var_transform: VarTransform::None,
+    is_integer: false,
+};
+pub const OUTPUT_ENERGY_METRIC: Metric = Metric {
+    metric: "nut_output_energy_joules_total",
+    help: "Output energy, integrated from the real power (or the load and nominal real power) in polling mode.",
+    type_: "counter",
+    unit: "joules",
+    nut_var: "ups.realpower",
+    var_transform: VarTransform::None,
+    is_integer: false,
+};
+// Exporter metrics, which are not part of NUT scrapes
+pub const PUSH_FAILURES_METRIC: Metric = Metric {
+    metric: "nut_exporter_push_failures_total",
+    help: "Number of pushes which failed after all retries, in push mode.",
+    type_: "counter",
+    unit: "",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: true,
+};
+pub const HTTP_REQUESTS_METRIC: Metric = Metric {
+    metric: "nut_exporter_http_requests_total",
+    help: "Number of HTTP requests, by path and status.",
+    type_: "counter",
+    unit: "",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: true,
+};
+pub const HTTP_REQUEST_DURATION_METRIC: Metric = Metric {
+    metric: "nut_exporter_http_request_duration_seconds",
+    help: "Time spent handling HTTP requests, by path and status.",
+    type_: "histogram",
+    unit: "seconds",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: false,
+};
+pub const SCRAPES_METRIC: Metric = Metric {
+    metric: "nut_exporter_scrapes_total",
+    help: "Number of NUT scrapes, by target.",
+    type_: "counter",
+    unit: "",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: true,
+};
+pub const SCRAPE_ERRORS_METRIC: Metric = Metric {
+    metric: "nut_exporter_scrape_errors_total",
+    help: "Number of failed NUT scrapes, by target.",
+    type_: "counter",
+    unit: "",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: true,
+};
+pub const SCRAPE_DURATION_METRIC: Metric = Metric {
+    metric: "nut_exporter_scrape_duration_seconds_total",
+    help: "Total time spent scraping NUT, by target.",
+    type_: "counter",
+    unit: "seconds",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: false,
+};
+pub const SCRAPE_CACHE_HITS_METRIC: Metric = Metric {
+    metric: "nut_exporter_scrape_cache_hits_total",
+    help: "Number of NUT scrapes served from the scrape cache.",
+    type_: "counter",
+    unit: "",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: true,
+};
+pub const SCRAPE_CACHE_MISSES_METRIC: Metric = Metric {
+    metric: "nut_exporter_scrape_cache_misses_total",
+    help: "Number of NUT scrapes not found in the scrape cache (excluding bypasses).",
+    type_: "counter",
+    unit: "",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: true,
+};
+pub const CONFIG_LAST_RELOAD_SUCCESSFUL_METRIC: Metric = Metric {
+    metric: "nut_exporter_config_last_reload_successful",
+    help: "If the last config reload was successful (or there was none).",
+    type_: "gauge",
+    unit: "",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: true,
+};
+pub const PROCESS_CPU_METRIC: Metric = Metric {
+    metric: "process_cpu_seconds_total",
+    help: "Total user and system CPU time spent.",
+    type_: "counter",
+    unit: "seconds",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: false,
+};
+pub const PROCESS_RESIDENT_MEMORY_METRIC: Metric = Metric {
+    metric: "process_resident_memory_bytes",
+    help: "Resident memory size.",
+    type_: "gauge",
+    unit: "bytes",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: true,
+};
+pub const PROCESS_OPEN_FDS_METRIC: Metric = Metric {
+    metric: "process_open_fds",
+    help: "Number of open file descriptors.",
+    type_: "gauge",
+    unit: "",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: true,
+};
+pub const PROCESS_START_TIME_METRIC: Metric = Metric {
+    metric: "process_start_time_seconds",
+    help: "Start time of the process since the Unix epoch.",
+    type_: "gauge",
+    unit: "seconds",
+    nut_var: "",
+    var_transform: VarTransform::None,
+    is_integer: false,
+};
 // Deprecated special metrics
 pub const OLD_SERVER_INFO_METRIC: Metric = Metric {
     metric: "nut_info",
@@ -90,7 +292,7 @@ pub const OLD_SERVER_INFO_METRIC: Metric = Metric {
 };
 
 // Basic metrics
-pub static BASIC_METRICS: [Metric; 44] = [
+pub static BASIC_METRICS: [Metric; 50] = [
     // Status, uptime, load
     Metric {
         metric: "nut_beeper_status",
@@ -98,7 +300,7 @@ pub static BASIC_METRICS: [Metric; 44] = [
         type_: "gauge",
         unit: "",
         nut_var: "ups.beeper.status",
-        var_transform: VarTransform::BeeperStatus,
+        var_transform: VarTransform::Map { values: &BEEPER_STATUS_VALUES, default: 0.0 },
         is_integer: true,
     },
     Metric {
@@ -128,6 +330,15 @@ pub static BASIC_METRICS: [Metric; 44] = [
         var_transform: VarTransform::None,
         is_integer: false,
     },
+    Metric {
+        metric: "nut_ups_test_timestamp_seconds",
+        help: "Date of the last UPS self-test, as a Unix timestamp.",
+        type_: "gauge",
+        unit: "seconds",
+        nut_var: "ups.test.date",
+        var_transform: VarTransform::Timestamp,
+        is_integer: true,
+    },
     // Battery
     Metric {
         metric: "nut_battery_charge",
@@ -192,6 +403,15 @@ pub static BASIC_METRICS: [Metric; 44] = [
         var_transform: VarTransform::None,
         is_integer: true,
     },
+    Metric {
+        metric: "nut_battery_energysave_delay_seconds",
+        help: "Delay before shutting down the load when in energy saving mode.",
+        type_: "gauge",
+        unit: "seconds",
+        nut_var: "battery.energysave.delay",
+        var_transform: MINUTES_TO_SECONDS,
+        is_integer: true,
+    },
     Metric {
         metric: "nut_delay_shutdown_seconds",
         help: "Interval to wait after shutdown with delay command.",
@@ -246,6 +466,42 @@ pub static BASIC_METRICS: [Metric; 44] = [
         var_transform: VarTransform::None,
         is_integer: false,
     },
+    Metric {
+        metric: "nut_battery_install_timestamp_seconds",
+        help: "Battery installation or last replacement date, as a Unix timestamp.",
+        type_: "gauge",
+        unit: "seconds",
+        nut_var: "battery.date",
+        var_transform: VarTransform::Timestamp,
+        is_integer: true,
+    },
+    Metric {
+        metric: "nut_battery_age_seconds",
+        help: "Time since battery installation or last replacement.",
+        type_: "gauge",
+        unit: "seconds",
+        nut_var: "battery.date",
+        var_transform: VarTransform::Age,
+        is_integer: true,
+    },
+    Metric {
+        metric: "nut_battery_manufacture_timestamp_seconds",
+        help: "Battery manufacturing date, as a Unix timestamp.",
+        type_: "gauge",
+        unit: "seconds",
+        nut_var: "battery.mfr.date",
+        var_transform: VarTransform::Timestamp,
+        is_integer: true,
+    },
+    Metric {
+        metric: "nut_battery_maintenance_timestamp_seconds",
+        help: "Battery last maintenance date, as a Unix timestamp.",
+        type_: "gauge",
+        unit: "seconds",
+        nut_var: "battery.date.maintenance",
+        var_transform: VarTransform::Timestamp,
+        is_integer: true,
+    },
     Metric {
         metric: "nut_battery_temperature_celsius",
         help: "Battery temperature.",
@@ -498,12 +754,16 @@ pub static BASIC_METRICS: [Metric; 44] = [
 lazy_static! {
     // Contains all metrics names, in insertion order
     pub static ref METRIC_NAMES: Vec<&'static str> = {
-        let mut vec: Vec<&'static str> = Vec::new();
-        vec.push(EXPORTER_INFO_METRIC.metric);
-        vec.push(SERVER_INFO_METRIC.metric);
-        vec.push(UPS_INFO_METRIC.metric);
-        vec.push(OLD_SERVER_INFO_METRIC.metric);
-        vec.push(UPS_STATUS_METRIC.metric);
+        let mut vec: Vec<&'static str> = vec![
+            EXPORTER_INFO_METRIC.metric,
+            SERVER_INFO_METRIC.metric,
+            UPS_INFO_METRIC.metric,
+            OLD_SERVER_INFO_METRIC.metric,
+            UPS_STATUS_METRIC.metric,
+            UPS_TEST_RESULT_METRIC.metric,
+            ESTIMATED_REAL_POWER_METRIC.metric,
+            ESTIMATED_POWER_METRIC.metric,
+        ];
         for metric in BASIC_METRICS.iter() {
             vec.push(metric.metric);
         }
@@ -518,6 +778,9 @@ lazy_static! {
         map.insert(UPS_INFO_METRIC.metric, &UPS_INFO_METRIC);
         map.insert(OLD_SERVER_INFO_METRIC.metric, &OLD_SERVER_INFO_METRIC);
         map.insert(UPS_STATUS_METRIC.metric, &UPS_STATUS_METRIC);
+        map.insert(UPS_TEST_RESULT_METRIC.metric, &UPS_TEST_RESULT_METRIC);
+        map.insert(ESTIMATED_REAL_POWER_METRIC.metric, &ESTIMATED_REAL_POWER_METRIC);
+        map.insert(ESTIMATED_POWER_METRIC.metric, &ESTIMATED_POWER_METRIC);
         for metric in BASIC_METRICS.iter() {
             map.insert(metric.metric, metric);
         }
@@ -527,8 +790,9 @@ lazy_static! {
     // Contains all metrics based on NUT vars, indexed by var
     pub static ref VAR_METRICS: HashMap<&'static str, Vec<&'static Metric>> = {
         let mut map: HashMap<&'static str, Vec<&'static Metric>> = HashMap::new();
+        map.entry(UPS_TEST_RESULT_METRIC.nut_var).or_default().push(&UPS_TEST_RESULT_METRIC);
         for metric in BASIC_METRICS.iter() {
-            map.entry(metric.nut_var).or_insert_with(Vec::new).push(metric);
+            map.entry(metric.nut_var).or_default().push(metric);
         }
         map
     };
@@ -548,6 +812,25 @@ pub fn print_metrics() {
     print_metric(&UPS_INFO_METRIC);
     print_metric(&OLD_SERVER_INFO_METRIC);
     print_metric(&UPS_STATUS_METRIC);
+    print_metric(&UPS_TEST_RESULT_METRIC);
+    print_metric(&ESTIMATED_REAL_POWER_METRIC);
+    print_metric(&ESTIMATED_POWER_METRIC);
+    print_metric(&UPS_STATUS_TRANSITIONS_METRIC);
+    print_metric(&UPS_STATUS_SECONDS_METRIC);
+    print_metric(&OUTPUT_ENERGY_METRIC);
+    print_metric(&PUSH_FAILURES_METRIC);
+    print_metric(&HTTP_REQUESTS_METRIC);
+    print_metric(&HTTP_REQUEST_DURATION_METRIC);
+    print_metric(&SCRAPES_METRIC);
+    print_metric(&SCRAPE_ERRORS_METRIC);
+    print_metric(&SCRAPE_DURATION_METRIC);
+    print_metric(&SCRAPE_CACHE_HITS_METRIC);
+    print_metric(&SCRAPE_CACHE_MISSES_METRIC);
+    print_metric(&CONFIG_LAST_RELOAD_SUCCESSFUL_METRIC);
+    print_metric(&PROCESS_CPU_METRIC);
+    print_metric(&PROCESS_RESIDENT_MEMORY_METRIC);
+    print_metric(&PROCESS_OPEN_FDS_METRIC);
+    print_metric(&PROCESS_START_TIME_METRIC);
     for metric in BASIC_METRICS.iter() {
         print_metric(metric);
     }
@@ -0,0 +1,452 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde_json::json;
+use url::Url;
+
+use crate::config::Config;
+use crate::metrics::{Metric, UPS_DESCRIPTION_PSEUDOVAR, UPS_STATUS_METRIC, VAR_METRICS, VarMap, VarTransform};
+use crate::poller::PollResult;
+
+const DEFAULT_MQTT_PORT: u16 = 1883;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// Enough for a few polls worth of messages while disconnected
+const REQUEST_CAPACITY: usize = 1000;
+// Home Assistant publishes this to "<discovery prefix>/status" when it (re)starts
+const BIRTH_PAYLOAD: &[u8] = b"online";
+
+pub struct MqttClient {
+    client: AsyncClient,
+    topic_prefix: String,
+    discovery_prefix: String,
+    expire_after: u64,
+    // Discovery config topics published since startup or since Home Assistant last came online (they're retained, so once is enough)
+    discovered: Arc<Mutex<HashSet<String>>>,
+}
+
+impl MqttClient {
+    // Connect to the broker in the background.
+    pub fn new(config: &Config, mqtt_url: &Url) -> MqttClient {
+        let host = mqtt_url.host_str().unwrap_or_default();
+        let port = mqtt_url.port().unwrap_or(DEFAULT_MQTT_PORT);
+        let mut options = MqttOptions::new(&config.mqtt_client_id, host, port);
+        options.set_keep_alive(KEEP_ALIVE);
+        if !mqtt_url.username().is_empty() {
+            options.set_credentials(mqtt_url.username(), mqtt_url.password().unwrap_or(""));
+        }
+
+        let (client, mut event_loop) = AsyncClient::new(options, REQUEST_CAPACITY);
+        let broker = format!("{}:{}", host, port);
+        let status_topic = format!("{}/status", config.mqtt_discovery_prefix);
+        let discovered = Arc::new(Mutex::new(HashSet::new()));
+        let event_client = client.clone();
+        let event_discovered = discovered.clone();
+        tokio::spawn(async move {
+            loop {
+                match event_loop.poll().await {
+                    // Subscribe on every connect, since the session isn't persistent
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        if let Err(err) = event_client.try_subscribe(&status_topic, QoS::AtLeastOnce) {
+                            log::warn!("Failed to subscribe to MQTT topic \"{}\": {}", status_topic, err);
+                        }
+                    },
+                    // Republish the discovery configs if Home Assistant restarted, in case it lost the retained ones
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        if is_birth_message(&publish.topic, &publish.payload, &status_topic) {
+                            log::debug!("Home Assistant came online, republishing MQTT discovery configs.");
+                            event_discovered.lock().unwrap().clear();
+                        }
+                    },
+                    Ok(_) => {},
+                    Err(err) => {
+                        log::warn!("MQTT connection to \"{}\" failed: {}", broker, err);
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    },
+                }
+            }
+        });
+
+        MqttClient {
+            client,
+            topic_prefix: config.mqtt_topic_prefix.clone(),
+            discovery_prefix: config.mqtt_discovery_prefix.clone(),
+            // Mark entities as unavailable in Home Assistant after missing a few polls
+            expire_after: config.poll_interval.as_secs().max(1) * 3,
+            discovered,
+        }
+    }
+
+    // Publish the state topics for all variables and the discovery configs for new entities.
+    pub fn publish(&self, results: &[PollResult]) {
+        let mut failures = 0;
+        for result in results.iter() {
+            for (ups, vars) in result.upses.iter() {
+                let node_id = build_node_id(&result.target, ups);
+                for (var, value) in vars.iter() {
+                    if var == UPS_DESCRIPTION_PSEUDOVAR {
+                        continue;
+                    }
+                    let state_topic = format!("{}/{}/{}", self.topic_prefix, node_id, var);
+                    if let Some(metric) = discovery_metric(var) {
+                        if !self.publish_discovery(&node_id, ups, vars, var, metric, &state_topic) {
+                            failures += 1;
+                        }
+                    }
+                    if self.client.try_publish(&state_topic, QoS::AtMostOnce, false, value.as_bytes()).is_err() {
+                        failures += 1;
+                    }
+                }
+            }
+        }
+        if failures > 0 {
+            log::warn!("Failed to queue {} MQTT message(s), the broker is probably unreachable.", failures);
+        }
+    }
+
+    // Publish the Home Assistant discovery config for a variable, unless already published.
+    fn publish_discovery(&self, node_id: &str, ups: &str, vars: &VarMap, var: &str, metric: &Metric, state_topic: &str) -> bool {
+        let config_topic = build_discovery_topic(&self.discovery_prefix, node_id, var);
+        let mut discovered = self.discovered.lock().unwrap();
+        if discovered.contains(&config_topic) {
+            return true;
+        }
+
+        let payload = build_discovery_payload(node_id, ups, vars, var, metric, state_topic, self.expire_after);
+        if self.client.try_publish(&config_topic, QoS::AtLeastOnce, true, payload.to_string()).is_err() {
+            return false;
+        }
+        discovered.insert(config_topic);
+        true
+    }
+}
+
+fn is_birth_message(topic: &str, payload: &[u8], status_topic: &str) -> bool {
+    topic == status_topic && payload == BIRTH_PAYLOAD
+}
+
+fn build_discovery_topic(discovery_prefix: &str, node_id: &str, var: &str) -> String {
+    format!("{}/sensor/{}/{}/config", discovery_prefix, node_id, var.replace('.', "_"))
+}
+
+// Build the Home Assistant discovery config for the sensor entity of a variable.
+fn build_discovery_payload(node_id: &str, ups: &str, vars: &VarMap, var: &str, metric: &Metric, state_topic: &str, expire_after: u64) -> serde_json::Value {
+    let mut payload = json!({
+        "name": entity_name(metric),
+        "unique_id": format!("{}_{}", node_id, var.replace('.', "_")),
+        "state_topic": state_topic,
+        "expire_after": expire_after,
+        "device": build_device(node_id, ups, vars),
+    });
+    // Only report units etc. for numeric variables, the others are exposed as plain text sensors
+    if metric.type_ == "gauge" && matches!(metric.var_transform, VarTransform::None | VarTransform::Percentage) {
+        payload["state_class"] = json!("measurement");
+        if let Some(unit) = unit_of_measurement(metric) {
+            payload["unit_of_measurement"] = json!(unit);
+        }
+        if let Some(device_class) = device_class(metric) {
+            payload["device_class"] = json!(device_class);
+        }
+    }
+
+    payload
+}
+
+// Get the metric to derive the discovery config from, if the variable should be discovered.
+fn discovery_metric(var: &str) -> Option<&'static Metric> {
+    if var == UPS_STATUS_METRIC.nut_var {
+        return Some(&UPS_STATUS_METRIC);
+    }
+    let metric = *VAR_METRICS.get(var)?.first()?;
+    match metric.var_transform {
+        // Dates are not in a format Home Assistant understands
+        VarTransform::Timestamp | VarTransform::Age => None,
+        _ => Some(metric),
+    }
+}
+
+// Home Assistant object IDs may only contain letters, digits, underscores and hyphens.
+fn build_node_id(target: &str, ups: &str) -> String {
+    format!("{}_{}", target, ups).chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect()
+}
+
+// Use the first sentence of the metric help (e.g. "Battery level").
+fn entity_name(metric: &Metric) -> &'static str {
+    metric.help.split('.').next().unwrap_or(metric.help).trim()
+}
+
+// Units of the raw NUT variables, which is what the state topics contain.
+fn unit_of_measurement(metric: &Metric) -> Option<&'static str> {
+    if metric.var_transform == VarTransform::Percentage {
+        return Some("%");
+    }
+    if is_apparent_power(metric) {
+        return Some("VA");
+    }
+    match metric.unit {
+        "volts" => Some("V"),
+        "amperes" => Some("A"),
+        "watts" => Some("W"),
+        "hertz" => Some("Hz"),
+        "celsius" => Some("°C"),
+        "seconds" => Some("s"),
+        _ => None,
+    }
+}
+
+fn device_class(metric: &Metric) -> Option<&'static str> {
+    if metric.var_transform == VarTransform::Percentage {
+        return match metric.nut_var.starts_with("battery.charge") {
+            true => Some("battery"),
+            false => None,
+        };
+    }
+    if is_apparent_power(metric) {
+        return Some("apparent_power");
+    }
+    match metric.unit {
+        "volts" => Some("voltage"),
+        "amperes" => Some("current"),
+        "watts" => Some("power"),
+        "hertz" => Some("frequency"),
+        "celsius" => Some("temperature"),
+        "seconds" => Some("duration"),
+        _ => None,
+    }
+}
+
+// The NUT "power" variables are the apparent power in VA (the metrics use watts), unlike the "realpower" variables.
+fn is_apparent_power(metric: &Metric) -> bool {
+    metric.unit == "watts" && !metric.nut_var.contains("realpower")
+}
+
+// Describe the UPS as a Home Assistant device, using the same variables as the UPS info metric.
+fn build_device(node_id: &str, ups: &str, vars: &VarMap) -> serde_json::Value {
+    let first_var = |names: &[&str]| names.iter().find_map(|name| vars.get(*name)).cloned();
+    let mut device = json!({
+        "identifiers": [node_id],
+        "name": ups,
+    });
+    if let Some(manufacturer) = first_var(&["device.mfr", "ups.mfr"]) {
+        device["manufacturer"] = json!(manufacturer);
+    }
+    if let Some(model) = first_var(&["device.model", "ups.model"]) {
+        device["model"] = json!(model);
+    }
+    if let Some(serial) = first_var(&["device.serial", "ups.serial"]) {
+        device["serial_number"] = json!(serial);
+    }
+    if let Some(firmware) = first_var(&["ups.firmware"]) {
+        device["sw_version"] = json!(firmware);
+    }
+
+    device
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::net::tcp::OwnedReadHalf;
+    use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+    use url::Url;
+
+    use crate::config::read_config;
+    use crate::labels::UpsLabelMap;
+    use crate::metrics::{UPS_STATUS_METRIC, UpsVarMap, VarMap};
+    use crate::poller::PollResult;
+
+    use super::{MqttClient, build_discovery_payload, build_discovery_topic, build_node_id, discovery_metric, is_birth_message};
+
+    // Messages received by the stand-in broker
+    #[derive(Debug, PartialEq)]
+    enum BrokerEvent {
+        Subscribe(String),
+        Publish { topic: String, retain: bool },
+    }
+
+    // Start a local stand-in for the MQTT broker, which accepts a single client and handles just enough of MQTT 3.1.1.
+    // Returns the received messages and a sender for (topic, payload) messages to send to the client.
+    async fn start_broker() -> (Url, UnboundedReceiver<BrokerEvent>, UnboundedSender<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("mqtt://{}", listener.local_addr().unwrap())).unwrap();
+        let (event_tx, event_rx) = unbounded_channel();
+        let (message_tx, mut message_rx) = unbounded_channel::<(String, String)>();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+            loop {
+                let response = tokio::select! {
+                    packet = read_packet(&mut reader) => {
+                        let (header, body) = match packet {
+                            Some(packet) => packet,
+                            None => break,
+                        };
+                        let topic = || String::from_utf8(body[2..2 + u16::from_be_bytes([body[0], body[1]]) as usize].to_vec()).unwrap();
+                        match header >> 4 {
+                            // CONNECT, answered with CONNACK
+                            1 => vec![0x20, 2, 0, 0],
+                            // PUBLISH, answered with PUBACK for QoS 1
+                            3 => {
+                                let topic = topic();
+                                let _ = event_tx.send(BrokerEvent::Publish { retain: header & 0x01 != 0, topic: topic.clone() });
+                                match (header >> 1) & 0x03 {
+                                    0 => Vec::new(),
+                                    _ => vec![0x40, 2, body[2 + topic.len()], body[3 + topic.len()]],
+                                }
+                            },
+                            // SUBSCRIBE (after the packet ID), answered with SUBACK
+                            8 => {
+                                let topic = String::from_utf8(body[4..4 + u16::from_be_bytes([body[2], body[3]]) as usize].to_vec()).unwrap();
+                                let _ = event_tx.send(BrokerEvent::Subscribe(topic));
+                                vec![0x90, 3, body[0], body[1], 1]
+                            },
+                            // PINGREQ, answered with PINGRESP
+                            12 => vec![0xD0, 0],
+                            _ => Vec::new(),
+                        }
+                    },
+                    Some((topic, payload)) = message_rx.recv() => {
+                        // PUBLISH with QoS 0
+                        let mut packet = vec![0x30, (2 + topic.len() + payload.len()) as u8];
+                        packet.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+                        packet.extend_from_slice(topic.as_bytes());
+                        packet.extend_from_slice(payload.as_bytes());
+                        packet
+                    },
+                };
+                if writer.write_all(&response).await.is_err() {
+                    break;
+                }
+            }
+        });
+        (url, event_rx, message_tx)
+    }
+
+    async fn read_packet(reader: &mut OwnedReadHalf) -> Option<(u8, Vec<u8>)> {
+        let header = reader.read_u8().await.ok()?;
+        let mut length = 0usize;
+        for shift in (0..28).step_by(7) {
+            let byte = reader.read_u8().await.ok()?;
+            length |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body).await.ok()?;
+        Some((header, body))
+    }
+
+    // Publish the results until a message for the topic is received, returning the received messages.
+    async fn publish_until(mqtt_client: &MqttClient, results: &[PollResult], events: &mut UnboundedReceiver<BrokerEvent>, topic: &str) -> Vec<BrokerEvent> {
+        let mut received = Vec::new();
+        for _ in 0..50 {
+            mqtt_client.publish(results);
+            while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(100), events.recv()).await {
+                received.push(event);
+            }
+            if received.iter().any(|event| matches!(event, BrokerEvent::Publish { topic: event_topic, .. } if event_topic == topic)) {
+                return received;
+            }
+        }
+        panic!("No message for topic \"{}\", received: {:?}", topic, received);
+    }
+
+    #[test]
+    fn discovery_topics_are_sanitized() {
+        let node_id = build_node_id("nut-server.local:3493", "ups 1");
+        assert_eq!(node_id, "nut-server_local_3493_ups_1");
+        assert_eq!(build_discovery_topic("homeassistant", &node_id, "battery.charge"), "homeassistant/sensor/nut-server_local_3493_ups_1/battery_charge/config");
+    }
+
+    #[test]
+    fn discovery_payloads_describe_the_entity() {
+        let vars: VarMap = HashMap::from([
+            ("device.mfr".to_owned(), "Eaton".to_owned()),
+            ("ups.mfr".to_owned(), "Ignored".to_owned()),
+            ("ups.model".to_owned(), "5P".to_owned()),
+            ("ups.serial".to_owned(), "1234".to_owned()),
+        ]);
+        let device = json!({ "identifiers": ["nut_ups"], "name": "ups", "manufacturer": "Eaton", "model": "5P", "serial_number": "1234" });
+
+        let metric = discovery_metric("battery.charge").unwrap();
+        assert_eq!(build_discovery_payload("nut_ups", "ups", &vars, "battery.charge", metric, "nut/nut_ups/battery.charge", 30), json!({
+            "name": "Battery level",
+            "unique_id": "nut_ups_battery_charge",
+            "state_topic": "nut/nut_ups/battery.charge",
+            "expire_after": 30,
+            "device": device,
+            "state_class": "measurement",
+            "unit_of_measurement": "%",
+            "device_class": "battery",
+        }));
+
+        let metric = discovery_metric("input.voltage").unwrap();
+        let payload = build_discovery_payload("nut_ups", "ups", &vars, "input.voltage", metric, "nut/nut_ups/input.voltage", 30);
+        assert_eq!(payload["unit_of_measurement"], "V");
+        assert_eq!(payload["device_class"], "voltage");
+
+        let metric = discovery_metric("ups.power").unwrap();
+        let payload = build_discovery_payload("nut_ups", "ups", &vars, "ups.power", metric, "nut/nut_ups/ups.power", 30);
+        assert_eq!(payload["unit_of_measurement"], "VA");
+        assert_eq!(payload["device_class"], "apparent_power");
+        let metric = discovery_metric("ups.realpower").unwrap();
+        let payload = build_discovery_payload("nut_ups", "ups", &vars, "ups.realpower", metric, "nut/nut_ups/ups.realpower", 30);
+        assert_eq!(payload["unit_of_measurement"], "W");
+        assert_eq!(payload["device_class"], "power");
+
+        // Non-numeric variables are plain text sensors
+        let metric = discovery_metric(UPS_STATUS_METRIC.nut_var).unwrap();
+        let payload = build_discovery_payload("nut_ups", "ups", &vars, UPS_STATUS_METRIC.nut_var, metric, "nut/nut_ups/ups.status", 30);
+        assert_eq!(payload["unique_id"], "nut_ups_ups_status");
+        assert!(payload.get("state_class").is_none() && payload.get("unit_of_measurement").is_none() && payload.get("device_class").is_none());
+
+        // Dates and unknown variables aren't discovered
+        assert!(discovery_metric("battery.date").is_none());
+        assert!(discovery_metric("unknown.var").is_none());
+    }
+
+    #[test]
+    fn birth_messages_are_detected() {
+        assert!(is_birth_message("homeassistant/status", b"online", "homeassistant/status"));
+        assert!(!is_birth_message("homeassistant/status", b"offline", "homeassistant/status"));
+        assert!(!is_birth_message("other/status", b"online", "homeassistant/status"));
+    }
+
+    #[tokio::test]
+    async fn discovery_configs_are_retained_and_republished_on_birth() {
+        let (url, mut events, messages) = start_broker().await;
+        let mut config = read_config();
+        config.mqtt_url = Some(url.clone());
+        let mqtt_client = MqttClient::new(&config, &url);
+        let status_topic = format!("{}/status", config.mqtt_discovery_prefix);
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap();
+        assert_eq!(event, Some(BrokerEvent::Subscribe(status_topic.clone())));
+
+        let vars: VarMap = HashMap::from([("battery.charge".to_owned(), "100".to_owned())]);
+        let upses: UpsVarMap = HashMap::from([("alpha".to_owned(), vars)]);
+        let ups_labels: UpsLabelMap = HashMap::from([("alpha".to_owned(), Vec::new())]);
+        let results = vec![PollResult { target: "nut-server:3493".to_owned(), upses, nut_version: "2.8.0".to_owned(), ups_labels }];
+        let config_topic = "homeassistant/sensor/nut-server_3493_alpha/battery_charge/config".to_owned();
+        let state_topic = "nut/nut-server_3493_alpha/battery.charge".to_owned();
+
+        // The discovery config is retained and only published once, while the states aren't retained
+        let received = publish_until(&mqtt_client, &results, &mut events, &config_topic).await;
+        assert!(received.contains(&BrokerEvent::Publish { topic: config_topic.clone(), retain: true }), "{:?}", received);
+        assert!(received.contains(&BrokerEvent::Publish { topic: state_topic.clone(), retain: false }), "{:?}", received);
+        let received = publish_until(&mqtt_client, &results, &mut events, &state_topic).await;
+        assert!(!received.iter().any(|event| matches!(event, BrokerEvent::Publish { topic, .. } if *topic == config_topic)), "{:?}", received);
+
+        // Home Assistant came online, so the discovery config is published again
+        messages.send((status_topic, "online".to_owned())).unwrap();
+        let received = publish_until(&mqtt_client, &results, &mut events, &config_topic).await;
+        assert!(received.contains(&BrokerEvent::Publish { topic: config_topic, retain: true }), "{:?}", received);
+    }
+}
@@ -1,12 +1,50 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use lazy_static::lazy_static;
 use regex::Regex;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
+use tokio::sync::{OnceCell, OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsConnector;
 
 use crate::common::ErrorResult;
 use crate::metrics::{NutVersion, UPS_DESCRIPTION_PSEUDOVAR, UpsVarMap, VarMap};
+use crate::self_metrics::record_scrape;
+use crate::targets::{NutTarget, NutTlsConfig};
+
+pub type UpsCmdMap = HashMap<String, Vec<String>>;
+
+// Plain TCP or TLS connection
+trait NutConnection: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> NutConnection for T {}
+type NutStream = BufReader<Box<dyn NutConnection>>;
+
+// Result of a scrape, shared by concurrent scrapes of the same target
+type SharedScrape = Arc<OnceCell<Result<(UpsVarMap, NutVersion), String>>>;
+
+lazy_static! {
+    // Connection limits per target address, removed when unused
+    static ref CONNECTION_LIMITS: Mutex<HashMap<String, Arc<Semaphore>>> = Mutex::new(HashMap::new());
+    // In-flight scrapes per target (see NutTarget::scrape_key)
+    static ref IN_FLIGHT_SCRAPES: Mutex<HashMap<Vec<u8>, SharedScrape>> = Mutex::new(HashMap::new());
+}
+
+// Held while connected to a target, to limit the number of connections per target address
+struct ConnectionPermit {
+    address: String,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+// Extra lists which are only queried when requested
+#[derive(Debug, Default)]
+pub struct NutExtras {
+    pub rws: Option<UpsVarMap>,
+    pub cmds: Option<UpsCmdMap>,
+}
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum NutQueryListState {
@@ -17,32 +55,208 @@ enum NutQueryListState {
     Error,
 }
 
-pub async fn scrape_nut(target: &str) -> ErrorResult<(UpsVarMap, NutVersion)> {
-    log::trace!("Connecting to NUT server: {}", target);
-    let raw_stream = match TcpStream::connect(target).await {
+// Scrape a target, sharing the result with concurrent scrapes of the same target.
+pub async fn scrape_nut(target: &NutTarget) -> ErrorResult<(UpsVarMap, NutVersion)> {
+    let scrape_key = target.scrape_key();
+    let shared_scrape = IN_FLIGHT_SCRAPES.lock().unwrap().entry(scrape_key.clone()).or_default().clone();
+    let result = shared_scrape.get_or_init(|| async {
+        let start_time = Instant::now();
+        let result = with_timeout(target, scrape_nut_inner(target)).await;
+        record_scrape(target, start_time.elapsed(), result.is_err());
+        // Scrapes starting after this one finished start a new scrape
+        let mut in_flight_scrapes = IN_FLIGHT_SCRAPES.lock().unwrap();
+        if matches!(in_flight_scrapes.get(&scrape_key), Some(in_flight_scrape) if Arc::ptr_eq(in_flight_scrape, &shared_scrape)) {
+            in_flight_scrapes.remove(&scrape_key);
+        }
+        result.map_err(|err| err.to_string())
+    }).await;
+
+    match result {
+        Ok(val) => Ok(val.clone()),
+        Err(err) => Err(err.clone().into()),
+    }
+}
+
+// Make scrapes starting after a config reload start a new scrape, instead of sharing one started with the old config.
+pub fn clear_in_flight_scrapes() {
+    IN_FLIGHT_SCRAPES.lock().unwrap().clear();
+}
+
+async fn scrape_nut_inner(target: &NutTarget) -> ErrorResult<(UpsVarMap, NutVersion)> {
+    let _permit = acquire_connection_permit(target).await;
+    let mut stream = connect_nut(target).await?;
+
+    match scrape_nut_upses(&mut stream, target).await {
+        Ok(val) => Ok(val),
+        Err(err) => Err(format!("Failed to communicate with target: {}", err).into()),
+    }
+}
+
+// Like scrape_nut, but also query the RW vars and/or instant commands.
+pub async fn scrape_nut_with_extras(target: &NutTarget, include_rws: bool, include_cmds: bool) -> ErrorResult<(UpsVarMap, NutVersion, NutExtras)> {
+    let start_time = Instant::now();
+    let result = with_timeout(target, scrape_nut_with_extras_inner(target, include_rws, include_cmds)).await;
+    record_scrape(target, start_time.elapsed(), result.is_err());
+    result
+}
+
+async fn scrape_nut_with_extras_inner(target: &NutTarget, include_rws: bool, include_cmds: bool) -> ErrorResult<(UpsVarMap, NutVersion, NutExtras)> {
+    let _permit = acquire_connection_permit(target).await;
+    let mut stream = connect_nut(target).await?;
+
+    match scrape_nut_upses_with_extras(&mut stream, target, include_rws, include_cmds).await {
+        Ok(val) => Ok(val),
+        Err(err) => Err(format!("Failed to communicate with target: {}", err).into()),
+    }
+}
+
+// Only list the (filtered) UPSes of a target, e.g. for service discovery.
+pub async fn list_nut_upses(target: &NutTarget) -> ErrorResult<Vec<String>> {
+    with_timeout(target, list_nut_upses_inner(target)).await
+}
+
+async fn list_nut_upses_inner(target: &NutTarget) -> ErrorResult<Vec<String>> {
+    let _permit = acquire_connection_permit(target).await;
+    let mut stream = connect_nut(target).await?;
+    let mut upses: UpsVarMap = HashMap::new();
+    if let Err(err) = query_nut_upses(&mut stream, &mut upses).await {
+        return Err(format!("Failed to communicate with target: {}", err).into());
+    }
+
+    let mut ups_names: Vec<String> = upses.into_keys()
+        .filter(|ups| target.ups_filter.as_ref().map(|ups_filter| ups_filter.is_match(ups)).unwrap_or(true))
+        .collect();
+    ups_names.sort();
+    Ok(ups_names)
+}
+
+// Wait until the target address has fewer than the max number of connections.
+// The limit of the first target using an address applies until the address is unused.
+async fn acquire_connection_permit(target: &NutTarget) -> ConnectionPermit {
+    let semaphore = CONNECTION_LIMITS.lock().unwrap()
+        .entry(target.address.clone())
+        .or_insert_with(|| Arc::new(Semaphore::new(target.max_connections)))
+        .clone();
+    let permit = semaphore.clone().acquire_owned().await.unwrap();
+    ConnectionPermit { address: target.address.clone(), semaphore, permit: Some(permit) }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.permit.take();
+        // Remove the limit if no one else is using or waiting for it
+        let mut connection_limits = CONNECTION_LIMITS.lock().unwrap();
+        let is_current = matches!(connection_limits.get(&self.address), Some(semaphore) if Arc::ptr_eq(semaphore, &self.semaphore));
+        if is_current && Arc::strong_count(&self.semaphore) == 2 {
+            connection_limits.remove(&self.address);
+        }
+    }
+}
+
+// Fail the scrape if it takes longer than the target timeout.
+async fn with_timeout<T>(target: &NutTarget, future: impl Future<Output = ErrorResult<T>>) -> ErrorResult<T> {
+    match tokio::time::timeout(target.timeout, future).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("Timed out after {} seconds.", target.timeout.as_secs_f64()).into()),
+    }
+}
+
+async fn connect_nut(target: &NutTarget) -> ErrorResult<NutStream> {
+    log::trace!("Connecting to NUT server: {}", target.address);
+    let tcp_stream = match TcpStream::connect(&target.address).await {
         Ok(val) => val,
         Err(err) => return Err(format!("Failed to connect to target: {}", err).into()),
     };
-    let mut stream = BufReader::new(raw_stream);
+    let mut stream: NutStream = match &target.tls {
+        Some(tls) => match start_tls(tcp_stream, tls).await {
+            Ok(tls_stream) => BufReader::new(tls_stream),
+            Err(err) => return Err(format!("Failed to set up TLS with target: {}", err).into()),
+        },
+        None => BufReader::new(Box::new(tcp_stream)),
+    };
 
-    match scrape_nut_upses(&mut stream).await {
-        Ok(val) => Ok(val),
-        Err(err) => Err(format!("Failed to communicate with target: {}", err).into()),
+    if let Some(username) = &target.username {
+        if let Err(err) = query_nut_ok(&mut stream, &format!("USERNAME {}", quote_nut_arg(username)), "USERNAME").await {
+            return Err(format!("Failed to log in to target: {}", err).into());
+        }
+    }
+    if let Some(password) = &target.password {
+        if let Err(err) = query_nut_ok(&mut stream, &format!("PASSWORD {}", quote_nut_arg(password)), "PASSWORD").await {
+            return Err(format!("Failed to log in to target: {}", err).into());
+        }
+    }
+
+    Ok(stream)
+}
+
+// Upgrade the connection using the NUT STARTTLS command.
+async fn start_tls(mut tcp_stream: TcpStream, tls: &NutTlsConfig) -> ErrorResult<Box<dyn NutConnection>> {
+    tcp_stream.write_all(b"STARTTLS\n").await?;
+    log::trace!("NUT query sent: {}", "STARTTLS");
+    // The server sends nothing more before the handshake, so nothing is lost in the buffer
+    let line = BufReader::new(&mut tcp_stream).lines().next_line().await?.unwrap_or_default();
+    log::trace!("NUT query received: {}", line);
+    if !line.starts_with("OK") {
+        return Err(format!("Received error for STARTTLS: {}", line.strip_prefix("ERR ").unwrap_or(&line)).into());
+    }
+
+    let connector = TlsConnector::from(tls.client_config.clone());
+    let tls_stream = connector.connect(tls.server_name.clone(), tcp_stream).await?;
+    Ok(Box::new(tls_stream))
+}
+
+// Quote a command argument, as NUT args may contain spaces and quotes.
+fn quote_nut_arg(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Send a command which is expected to return "OK", logging it as "description" to avoid logging secrets.
+async fn query_nut_ok(stream: &mut NutStream, query: &str, description: &str) -> ErrorResult<()> {
+    let query_line = format!("{}\n", query);
+    stream.write_all(query_line.as_bytes()).await?;
+    log::trace!("NUT query sent: {}", description);
+    let line = stream.lines().next_line().await?.unwrap_or_default();
+    log::trace!("NUT query received: {}", line);
+    if !line.starts_with("OK") {
+        return Err(format!("Received error for query \"{}\": {}", description, line.strip_prefix("ERR ").unwrap_or(&line)).into());
     }
+
+    Ok(())
 }
 
-async fn scrape_nut_upses(stream: &mut BufReader<TcpStream>) -> ErrorResult<(UpsVarMap, NutVersion)> {
+async fn scrape_nut_upses(stream: &mut NutStream, target: &NutTarget) -> ErrorResult<(UpsVarMap, NutVersion)> {
     let mut upses: UpsVarMap = HashMap::new();
     let mut nut_version: NutVersion = "".to_owned();
 
     query_nut_version(stream, &mut nut_version).await?;
     query_nut_upses(stream, &mut upses).await?;
+    if let Some(ups_filter) = &target.ups_filter {
+        upses.retain(|ups, _| ups_filter.is_match(ups));
+    }
     query_nut_vars(stream, &mut upses).await?;
 
     Ok((upses, nut_version))
 }
 
-async fn query_nut_version(stream: &mut BufReader<TcpStream>, nut_version: &mut NutVersion) -> ErrorResult<()> {
+async fn scrape_nut_upses_with_extras(stream: &mut NutStream, target: &NutTarget, include_rws: bool, include_cmds: bool) -> ErrorResult<(UpsVarMap, NutVersion, NutExtras)> {
+    let (upses, nut_version) = scrape_nut_upses(stream, target).await?;
+    let mut extras = NutExtras::default();
+
+    if include_rws {
+        let mut rws: UpsVarMap = upses.keys().map(|ups| (ups.clone(), HashMap::new())).collect();
+        query_nut_rws(stream, &mut rws).await?;
+        extras.rws = Some(rws);
+    }
+    if include_cmds {
+        let mut cmds: UpsCmdMap = upses.keys().map(|ups| (ups.clone(), Vec::new())).collect();
+        query_nut_cmds(stream, &mut cmds).await?;
+        extras.cmds = Some(cmds);
+    }
+
+    Ok((upses, nut_version, extras))
+}
+
+async fn query_nut_version(stream: &mut NutStream, nut_version: &mut NutVersion) -> ErrorResult<()> {
     lazy_static! {
         static ref VERSION_PATTERN: Regex = Regex::new(r#"upsd (?P<version>.+) -"#).unwrap();
     }
@@ -65,7 +279,7 @@ async fn query_nut_version(stream: &mut BufReader<TcpStream>, nut_version: &mut
     Ok(())
 }
 
-async fn query_nut_upses(stream: &mut BufReader<TcpStream>, upses: &mut UpsVarMap) -> ErrorResult<()> {
+async fn query_nut_upses(stream: &mut NutStream, upses: &mut UpsVarMap) -> ErrorResult<()> {
     lazy_static! {
         static ref UPS_PATTERN: Regex = Regex::new(r#"^UPS\s+(?P<ups>[\S]+)\s+"(?P<desc>[^"]*)"$"#).unwrap();
     }
@@ -93,7 +307,7 @@ async fn query_nut_upses(stream: &mut BufReader<TcpStream>, upses: &mut UpsVarMa
     Ok(())
 }
 
-async fn query_nut_vars(stream: &mut BufReader<TcpStream>, upses: &mut UpsVarMap) -> ErrorResult<()> {
+async fn query_nut_vars(stream: &mut NutStream, upses: &mut UpsVarMap) -> ErrorResult<()> {
     lazy_static! {
         static ref VAR_PATTERN: Regex = Regex::new(r#"^VAR\s+(?P<ups>[\S]+)\s+(?P<var>[\S]+)\s+"(?P<val>[^"]*)"$"#).unwrap();
     }
@@ -121,7 +335,61 @@ async fn query_nut_vars(stream: &mut BufReader<TcpStream>, upses: &mut UpsVarMap
     Ok(())
 }
 
-async fn query_nut_list<F>(stream: &mut BufReader<TcpStream>, query: &str, mut line_consumer: F) -> ErrorResult<()>
+async fn query_nut_rws(stream: &mut NutStream, rws: &mut UpsVarMap) -> ErrorResult<()> {
+    lazy_static! {
+        static ref RW_PATTERN: Regex = Regex::new(r#"^RW\s+(?P<ups>[\S]+)\s+(?P<var>[\S]+)\s+"(?P<val>[^"]*)"$"#).unwrap();
+    }
+
+    for (ups, vars) in rws.iter_mut() {
+        let line_consumer = |line: &str| {
+            let captures_opt = RW_PATTERN.captures(line);
+            match captures_opt {
+                Some(captures) => {
+                    let variable = captures["var"].to_owned();
+                    let value = captures["val"].to_owned();
+                    vars.insert(variable, value);
+                },
+                None => {
+                    return Err("Malformed list element for RW list query.".into());
+                },
+            }
+
+            Ok(())
+        };
+
+        query_nut_list(stream, format!("LIST RW {}", ups).as_str(), line_consumer).await?;
+    }
+
+    Ok(())
+}
+
+async fn query_nut_cmds(stream: &mut NutStream, cmds: &mut UpsCmdMap) -> ErrorResult<()> {
+    lazy_static! {
+        static ref CMD_PATTERN: Regex = Regex::new(r#"^CMD\s+(?P<ups>[\S]+)\s+(?P<cmd>[\S]+)$"#).unwrap();
+    }
+
+    for (ups, ups_cmds) in cmds.iter_mut() {
+        let line_consumer = |line: &str| {
+            let captures_opt = CMD_PATTERN.captures(line);
+            match captures_opt {
+                Some(captures) => {
+                    ups_cmds.push(captures["cmd"].to_owned());
+                },
+                None => {
+                    return Err("Malformed list element for CMD list query.".into());
+                },
+            }
+
+            Ok(())
+        };
+
+        query_nut_list(stream, format!("LIST CMD {}", ups).as_str(), line_consumer).await?;
+    }
+
+    Ok(())
+}
+
+async fn query_nut_list<F>(stream: &mut NutStream, query: &str, mut line_consumer: F) -> ErrorResult<()>
         where F: FnMut(&str) -> ErrorResult<()> + Send {
     let query_line = format!("{}\n", query);
     stream.write_all(query_line.as_bytes()).await?;
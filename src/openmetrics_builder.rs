@@ -1,88 +1,256 @@
 use std::fmt::Write as _;
 use std::collections::{HashMap, HashSet};
 
+use chrono::{NaiveDate, TimeZone, Utc};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::labels::{LabelList, UpsLabelMap};
 use crate::meta::APP_VERSION;
-use crate::metrics::{EXPORTER_INFO_METRIC, Metric, METRIC_NAMES, METRICS, OLD_SERVER_INFO_METRIC, SERVER_INFO_METRIC, UPS_DESCRIPTION_PSEUDOVAR, UPS_INFO_METRIC, UPS_STATUS_ELEMENTS, UPS_STATUS_METRIC, UpsVarMap, VAR_METRICS, VarMap, VarTransform};
+use crate::metrics::{ESTIMATED_POWER_METRIC, ESTIMATED_REAL_POWER_METRIC, EXPORTER_INFO_METRIC, Metric, METRIC_NAMES, METRICS, OLD_SERVER_INFO_METRIC, SERVER_INFO_METRIC, UPS_DESCRIPTION_PSEUDOVAR, UPS_INFO_METRIC, UPS_STATUS_ELEMENTS, UPS_STATUS_METRIC, UpsVarMap, VAR_METRICS, VarMap, VarTransform};
+
+// A single sample of a metric family.
+// For state sets, the state is always the last label.
+// For histograms, the cumulative buckets have the "le" label last (where the "+Inf" bucket is also the count),
+// followed by the sum without the "le" label.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub labels: LabelList,
+    pub value: f64,
+}
+
+// A sample with the full sample name, as exposed
+pub struct NamedSample<'a> {
+    pub name: String,
+    pub labels: &'a [(String, String)],
+    pub value: f64,
+    pub is_integer: bool,
+}
+
+// Metric families with their samples, in stable order
+pub type MetricFamilies = Vec<(&'static Metric, Vec<Sample>)>;
+
+// Text exposition formats. The samples are the same for both, but the metadata differs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TextFormat {
+    // OpenMetrics 1.0.0
+    OpenMetrics,
+    // Prometheus text format 0.0.4
+    Prometheus,
+}
+
+pub fn build_openmetrics_content(families: &MetricFamilies) -> String {
+    print_text_content(families, TextFormat::OpenMetrics)
+}
 
-pub fn build_openmetrics_content(upses: &UpsVarMap, nut_version: &str) -> String {
+pub fn build_prometheus_content(families: &MetricFamilies) -> String {
+    print_text_content(families, TextFormat::Prometheus)
+}
+
+// Build samples for all metrics for all UPSes, independent of the output format.
+pub fn build_metric_families(upses: &UpsVarMap, nut_version: &str, ups_labels: &UpsLabelMap) -> MetricFamilies {
     // Use vec for stable ordering of metrics within a metric family
-    let mut metric_lines: HashMap<String, Vec<String>> = METRICS.keys().map(|m| ((*m).to_owned(), Vec::new())).collect();
+    let mut metric_samples: HashMap<&'static str, Vec<Sample>> = METRICS.keys().map(|m| (*m, Vec::new())).collect();
 
     // Exporter and server special
-    metric_lines.get_mut(EXPORTER_INFO_METRIC.metric).unwrap().push(print_exporter_info_metric());
-    metric_lines.get_mut(SERVER_INFO_METRIC.metric).unwrap().push(print_server_info_metric(nut_version));
-    metric_lines.get_mut(OLD_SERVER_INFO_METRIC.metric).unwrap().push(print_old_server_info_metric(nut_version));
+    metric_samples.get_mut(EXPORTER_INFO_METRIC.metric).unwrap().push(build_version_info_sample(APP_VERSION));
+    metric_samples.get_mut(SERVER_INFO_METRIC.metric).unwrap().push(build_version_info_sample(nut_version));
+    metric_samples.get_mut(OLD_SERVER_INFO_METRIC.metric).unwrap().push(build_version_info_sample(nut_version));
 
-    // Generate metric lines for all vars for all UPSes
+    // Generate samples for all vars for all UPSes
     for (ups, vars) in upses.iter() {
+        // Common UPS labels
+        let labels = build_ups_labels(ups, ups_labels);
         // UPS special
-        metric_lines.get_mut(UPS_INFO_METRIC.metric).unwrap().push(print_ups_info_metric(ups, vars));
-        metric_lines.get_mut(UPS_STATUS_METRIC.metric).unwrap().append(&mut print_ups_status_metrics(ups, vars));
+        metric_samples.get_mut(UPS_INFO_METRIC.metric).unwrap().push(build_ups_info_sample(&labels, vars));
+        metric_samples.get_mut(UPS_STATUS_METRIC.metric).unwrap().append(&mut build_ups_status_samples(&labels, vars));
+        // Estimated power, only if not reported
+        if !vars.contains_key("ups.realpower") {
+            if let Some(value) = estimate_power(vars, "ups.realpower.nominal") {
+                metric_samples.get_mut(ESTIMATED_REAL_POWER_METRIC.metric).unwrap().push(Sample { labels: labels.clone(), value });
+            }
+        }
+        if !vars.contains_key("ups.power") {
+            if let Some(value) = estimate_power(vars, "ups.power.nominal") {
+                metric_samples.get_mut(ESTIMATED_POWER_METRIC.metric).unwrap().push(Sample { labels: labels.clone(), value });
+            }
+        }
         // UPS vars
         for (var, val) in vars.iter() {
             if let Some(metrics) = VAR_METRICS.get(var.as_str()) {
                 for metric in metrics {
-                    if let Some(var_line) = print_basic_var_metric(ups, val, metric) {
-                        metric_lines.get_mut(metric.metric).unwrap().push(var_line);
+                    if let Some(value) = transform_var_value(ups, val, metric) {
+                        let samples = metric_samples.get_mut(metric.metric).unwrap();
+                        match metric.var_transform {
+                            VarTransform::StateMap { label, states } => {
+                                samples.extend(states.iter().enumerate().map(|(i, (state, _))| build_stateset_sample(&labels, label, state, i as f64 == value)));
+                            },
+                            _ => samples.push(Sample { labels: labels.clone(), value }),
+                        }
                     }
                 }
             }
         }
     }
 
-    // Print metric info and then all dimensions together
     // Use METRIC_NAMES vec for stable ordering of metric families
-    let mut builder: String = String::new();
+    let mut families: MetricFamilies = Vec::new();
     for metric_name in METRIC_NAMES.iter() {
         let metric = METRICS[metric_name];
-        if let Some(lines) = metric_lines.get(metric.metric) {
-            if !lines.is_empty() {
-                builder.push_str(&print_metric_metadata(metric));
-                builder.push_str(&lines.concat());
+        if let Some(samples) = metric_samples.remove(metric.metric) {
+            if !samples.is_empty() {
+                families.push((metric, samples));
             }
         }
     }
-    builder.push_str("# EOF\n");
+
+    families
+}
+
+fn print_text_content(families: &MetricFamilies, format: TextFormat) -> String {
+    // Print metric info and then all dimensions together
+    let mut builder: String = String::new();
+    for (metric, samples) in families.iter() {
+        builder.push_str(&print_metric_metadata(metric, format));
+        for sample in samples.iter() {
+            builder.push_str(&print_sample(metric, sample, format));
+        }
+    }
+    if format == TextFormat::OpenMetrics {
+        builder.push_str("# EOF\n");
+    }
+
+    builder
+}
+
+// Get the help text, including the NUT var (if any).
+pub fn metric_help(metric: &Metric) -> String {
+    match metric.nut_var.is_empty() {
+        true => metric.help.to_owned(),
+        false => format!("{} (\"{}\")", metric.help, metric.nut_var),
+    }
+}
+
+fn print_metric_metadata(metric: &Metric, format: TextFormat) -> String {
+    let help = metric_help(metric);
+
+    let mut builder: String = String::new();
+    match format {
+        TextFormat::OpenMetrics => {
+            let family = openmetrics_family_name(metric);
+            let _ = writeln!(builder, "# TYPE {} {}", family, metric.type_);
+            if !metric.unit.is_empty() {
+                let _ = writeln!(builder, "# UNIT {} {}", family, metric.unit);
+            }
+            let _ = writeln!(builder, "# HELP {} {}", family, escape_om(&help));
+        },
+        TextFormat::Prometheus => {
+            // The Prometheus format has no info or stateset types and no units
+            let _ = writeln!(builder, "# HELP {} {}", metric.metric, escape_prometheus_help(&help));
+            let _ = writeln!(builder, "# TYPE {} {}", metric.metric, prometheus_type(metric));
+        },
+    }
 
     builder
 }
 
-fn print_metric_metadata(metric: &Metric) -> String {
+fn print_sample(metric: &Metric, sample: &Sample, format: TextFormat) -> String {
     let mut builder: String = String::new();
-    let _ = writeln!(builder, "# TYPE {} {}", metric.metric, metric.type_);
-    let _ = writeln!(builder, "# UNIT {} {}", metric.metric, metric.unit);
-    if !metric.nut_var.is_empty() {
-        let _ = writeln!(builder, "# HELP {} {} (\"{}\")", metric.metric, metric.help, metric.nut_var);
-    } else {
-        let _ = writeln!(builder, "# HELP {} {}", metric.metric, metric.help);
+    for named_sample in name_samples(metric, sample) {
+        let mut labels_str = String::new();
+        for (name, value) in named_sample.labels.iter() {
+            let _ = write!(labels_str, "{}{}=\"{}\"", if labels_str.is_empty() { "" } else { "," }, name, escape_om(value));
+        }
+        // OpenMetrics requires the state to be in a label named after the metric family.
+        // The short label is kept for compatibility and for the Prometheus format.
+        if format == TextFormat::OpenMetrics && metric.type_ == "stateset" {
+            if let Some((_, state)) = named_sample.labels.last() {
+                let _ = write!(labels_str, ",{}=\"{}\"", metric.metric, escape_om(state));
+            }
+        }
+
+        // Make sure floats always contains a decimal point and that ints never do
+        let value_str = match (named_sample.is_integer, named_sample.value) {
+            (_, value) if value.is_nan() => "NaN".to_owned(),
+            (_, value) if value.is_infinite() => match value.is_sign_positive() { true => "+Inf".to_owned(), false => "-Inf".to_owned() },
+            (true, value) => format!("{:.0}", value),
+            (false, value) => format!("{:.17}", value),
+        };
+
+        let _ = match labels_str.is_empty() {
+            true => writeln!(builder, "{} {}", named_sample.name, value_str),
+            false => writeln!(builder, "{}{{{}}} {}", named_sample.name, labels_str, value_str),
+        };
     }
 
     builder
 }
 
-fn print_exporter_info_metric() -> String {
-    let metric = EXPORTER_INFO_METRIC;
-    format!("{metric}{{version=\"{version}\"}} 1\n", metric=metric.metric, version=escape_om(APP_VERSION))
+// Get the exposed samples for a sample, which is split into the bucket and count samples or is the sum sample for histograms.
+pub fn name_samples<'a>(metric: &Metric, sample: &'a Sample) -> Vec<NamedSample<'a>> {
+    if metric.type_ != "histogram" {
+        return vec![NamedSample { name: metric.metric.to_owned(), labels: &sample.labels, value: sample.value, is_integer: metric.is_integer }];
+    }
+    match sample.labels.split_last() {
+        Some(((label, bound), series_labels)) if label == "le" => {
+            let mut named_samples = vec![NamedSample { name: format!("{}_bucket", metric.metric), labels: &sample.labels, value: sample.value, is_integer: true }];
+            if bound == "+Inf" {
+                named_samples.push(NamedSample { name: format!("{}_count", metric.metric), labels: series_labels, value: sample.value, is_integer: true });
+            }
+            named_samples
+        },
+        _ => vec![NamedSample { name: format!("{}_sum", metric.metric), labels: &sample.labels, value: sample.value, is_integer: false }],
+    }
 }
 
-fn print_server_info_metric(nut_version: &str) -> String {
-    let metric = SERVER_INFO_METRIC;
-    format!("{metric}{{version=\"{version}\"}} 1\n", metric=metric.metric, version=escape_om(nut_version))
+// Format a histogram bucket bound for the "le" label, always with a decimal point (e.g. "1.0").
+pub fn format_bucket_bound(bound: f64) -> String {
+    match bound.is_infinite() {
+        true => "+Inf".to_owned(),
+        false => format!("{:?}", bound),
+    }
 }
 
-fn print_old_server_info_metric(nut_version: &str) -> String {
-    let metric = OLD_SERVER_INFO_METRIC;
-    format!("{metric}{{version=\"{version}\"}} 1\n", metric=metric.metric, version=escape_om(nut_version))
+// OpenMetrics family names exclude the sample suffix of info metrics and counters (e.g. family "nut_ups" for samples "nut_ups_info").
+fn openmetrics_family_name(metric: &Metric) -> &'static str {
+    match metric.type_ {
+        "info" => metric.metric.strip_suffix("_info").unwrap_or(metric.metric),
+        "counter" => metric.metric.strip_suffix("_total").unwrap_or(metric.metric),
+        _ => metric.metric,
+    }
+}
+
+// Formats without the info and stateset types represent them as gauges.
+pub fn prometheus_type(metric: &Metric) -> &'static str {
+    match metric.type_ {
+        "info" | "stateset" => "gauge",
+        type_ => type_,
+    }
 }
 
-fn print_ups_info_metric(ups: &str, vars: &VarMap) -> String {
-    let metric = UPS_INFO_METRIC;
+fn build_version_info_sample(version: &str) -> Sample {
+    Sample { labels: vec![("version".to_owned(), version.to_owned())], value: 1f64 }
+}
+
+// Build the "ups" label and any extra labels for the UPS.
+pub fn build_ups_labels(ups: &str, ups_labels: &UpsLabelMap) -> LabelList {
+    let mut labels: LabelList = vec![("ups".to_owned(), ups.to_owned())];
+    if let Some(extra_labels) = ups_labels.get(ups) {
+        labels.extend(extra_labels.iter().cloned());
+    }
+
+    labels
+}
 
-    let mut labels_str = String::new();
-    let _ = write!(labels_str, "ups=\"{}\"", escape_om(ups));
+fn build_ups_info_sample(labels: &LabelList, vars: &VarMap) -> Sample {
+    let mut info_labels = labels.clone();
     let mut add_var_label = |name: &str, var: &str| {
+        // Skip if already added as an extra label
+        if labels.iter().any(|(label, _)| label == name) {
+            return;
+        }
         if let Some(value) = vars.get(var) {
-            let _ = write!(labels_str, ",{}=\"{}\"", escape_om(name), escape_om(value));
+            info_labels.push((name.to_owned(), value.clone()));
         }
     };
 
@@ -106,28 +274,34 @@ fn print_ups_info_metric(ups: &str, vars: &VarMap) -> String {
     add_var_label("type", "device.type");
     add_var_label("nut_version", "driver.version");
 
-    format!("{}{{{}}} 1\n",metric.metric, labels_str)
+    Sample { labels: info_labels, value: 1f64 }
 }
 
-fn print_ups_status_metrics(ups: &str, vars: &VarMap) -> Vec<String> {
+fn build_stateset_sample(labels: &LabelList, label: &str, state: &str, is_set: bool) -> Sample {
+    let mut state_labels = labels.clone();
+    state_labels.push((label.to_owned(), state.to_owned()));
+    Sample { labels: state_labels, value: match is_set { false => 0f64, true => 1f64 } }
+}
+
+fn build_ups_status_samples(labels: &LabelList, vars: &VarMap) -> Vec<Sample> {
     let metric = UPS_STATUS_METRIC;
-    let mut lines: Vec<String> = Vec::new();
 
     let status_raw = match vars.get(metric.nut_var) {
         Some(x) => x,
-        None => return lines,
+        None => return Vec::new(),
     };
     let statuses: HashSet<&str> = HashSet::from_iter(status_raw.split(' '));
 
-    for state in UPS_STATUS_ELEMENTS.iter() {
-        let value_num = match statuses.contains(state) { false => 0i64, true => 1i64 };
-        lines.push(format!("{metric}{{ups=\"{ups}\",status=\"{state}\"}} {value}\n", ups=ups, metric=metric.metric, state=state, value=value_num));
-    }
+    UPS_STATUS_ELEMENTS.iter().map(|state| build_stateset_sample(labels, "status", state, statuses.contains(state))).collect()
+}
 
-    lines
+// Estimate the power from the load (percent) and the nominal power var.
+pub fn estimate_power(vars: &VarMap, nominal_var: &str) -> Option<f64> {
+    let parse_var = |var: &str| vars.get(var).and_then(|value| value.parse::<f64>().ok()).filter(|value| value.is_finite());
+    Some(parse_var("ups.load")? / 100.0 * parse_var(nominal_var)?)
 }
 
-fn print_basic_var_metric(ups: &str, value: &str, metric: &Metric) -> Option<String> {
+fn transform_var_value(ups: &str, value: &str, metric: &Metric) -> Option<f64> {
     let result_value: f64 = match metric.var_transform {
         VarTransform::None => {
             match value.parse::<f64>() {
@@ -142,14 +316,24 @@ fn print_basic_var_metric(ups: &str, value: &str, metric: &Metric) -> Option<Str
             };
             num_value / 100f64
         },
-        VarTransform::BeeperStatus => {
-            match value {
-                "enabled" => 1f64,
-                "disabled" => 2f64,
-                "muted" => 3f64,
-                _ => 0f64,
+        VarTransform::Map { values, default } => {
+            match values.iter().find(|(key, _)| *key == value) {
+                Some((_, num_value)) => *num_value,
+                None => default,
             }
         },
+        VarTransform::StateMap { states, .. } => {
+            let value = value.trim().to_lowercase();
+            let state_index = states.iter().position(|(_, prefixes)| prefixes.iter().any(|prefix| value.starts_with(prefix)));
+            state_index.unwrap_or(states.len() - 1) as f64
+        },
+        VarTransform::Linear { scale, offset } => {
+            let num_value = match value.parse::<f64>() {
+                Ok(val) => val,
+                Err(_) => return None,
+            };
+            num_value * scale + offset
+        },
         VarTransform::OldUpsStatus => {
             // Remove the second component if present ("LB" etc.)
             let value_start = value.split_once(' ').map_or(value, |x| x.0);
@@ -160,15 +344,50 @@ fn print_basic_var_metric(ups: &str, value: &str, metric: &Metric) -> Option<Str
                 _ => 0f64,
             }
         },
+        VarTransform::Timestamp => {
+            parse_nut_date(ups, metric.nut_var, value)? as f64
+        },
+        VarTransform::Age => {
+            let timestamp = parse_nut_date(ups, metric.nut_var, value)?;
+            (Utc::now().timestamp() - timestamp) as f64
+        },
     };
 
-    // Make sure floats always contains a decimal point and that ints never do
-    let result_str = match metric.is_integer {
-        true => format!("{:.0}", result_value),
-        false => format!("{:.17}", result_value),
-    };
+    Some(result_value)
+}
+
+// Parse a date var as a Unix timestamp (at midnight UTC).
+// Drivers use a mix of formats: ISO ("YYYY-MM-DD"), "YYYY/MM/DD", "MM/DD/YY" and "MM/DD/YYYY".
+fn parse_nut_date(ups: &str, var: &str, value: &str) -> Option<i64> {
+    lazy_static! {
+        static ref YMD_DATE_PATTERN: Regex = Regex::new(r#"^(?P<year>[0-9]{4})[-/](?P<month>[0-9]{1,2})[-/](?P<day>[0-9]{1,2})$"#).unwrap();
+        static ref MDY_DATE_PATTERN: Regex = Regex::new(r#"^(?P<month>[0-9]{1,2})/(?P<day>[0-9]{1,2})/(?P<year>[0-9]{2}|[0-9]{4})$"#).unwrap();
+    }
 
-    Some(format!("{metric}{{ups=\"{ups}\"}} {value}\n", metric=metric.metric, ups=escape_om(ups), value=result_str))
+    let value = value.trim();
+    let captures_opt = YMD_DATE_PATTERN.captures(value).or_else(|| MDY_DATE_PATTERN.captures(value));
+    let date_opt = captures_opt.and_then(|captures| {
+        let mut year = captures["year"].parse::<i32>().ok()?;
+        let month = captures["month"].parse::<u32>().ok()?;
+        let day = captures["day"].parse::<u32>().ok()?;
+        // Two-digit years are assumed to be in the range 1970-2069
+        if captures["year"].len() == 2 {
+            year += if year < 70 { 2000 } else { 1900 };
+        }
+        NaiveDate::from_ymd_opt(year, month, day)
+    });
+
+    match date_opt.and_then(|date| date.and_hms_opt(0, 0, 0)) {
+        Some(datetime) => Some(Utc.from_utc_datetime(&datetime).timestamp()),
+        None => {
+            log::debug!("Failed to parse date for UPS \"{}\" var \"{}\": {}", ups, var, value);
+            None
+        },
+    }
+}
+
+fn escape_prometheus_help(raw_text: &str) -> String {
+    raw_text.replace('\\', r#"\\"#).replace('\n', r#"\n"#)
 }
 
 fn escape_om(raw_text: &str) -> String {
@@ -179,3 +398,308 @@ fn escape_om(raw_text: &str) -> String {
         _ => c.to_string(),
     }).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::time::Duration;
+
+    use crate::labels::UpsLabelMap;
+    use crate::metrics::{BEEPER_STATUS_VALUES, METRICS, MINUTES_TO_SECONDS, Metric, OUTPUT_ENERGY_METRIC, UPS_DESCRIPTION_PSEUDOVAR, UPS_STATUS_METRIC, UPS_STATUS_SECONDS_METRIC, UPS_STATUS_TRANSITIONS_METRIC, UPS_TEST_RESULT_METRIC, UPS_TEST_RESULT_STATES, UpsVarMap, VAR_METRICS, VarMap, VarTransform};
+    use crate::energy_tracker::track_energy;
+    use crate::poller::{PollResult, build_polled_families};
+    use crate::self_metrics::{build_self_metric_families, record_http_request, record_scrape};
+    use crate::status_tracker::track_statuses;
+    use crate::targets::NutTarget;
+
+    use super::{MetricFamilies, build_metric_families, build_openmetrics_content, build_prometheus_content, openmetrics_family_name, parse_nut_date, transform_var_value};
+
+    // Example var values which all metrics should be able to parse
+    fn example_var_value(var_transform: VarTransform) -> String {
+        match var_transform {
+            VarTransform::None | VarTransform::Percentage | VarTransform::Linear { .. } => "12.5".to_owned(),
+            VarTransform::Map { values, .. } => values[0].0.to_owned(),
+            VarTransform::StateMap { states, .. } => states[0].1[0].to_owned(),
+            VarTransform::OldUpsStatus => "OL CHRG".to_owned(),
+            VarTransform::Timestamp | VarTransform::Age => "2021-03-11".to_owned(),
+        }
+    }
+
+    // Parse a label set (without braces) and check the syntax, escaping and uniqueness.
+    fn parse_labels(labels_str: &str) -> Result<Vec<(String, String)>, String> {
+        let mut labels = Vec::new();
+        let mut chars = labels_str.chars().peekable();
+        while chars.peek().is_some() {
+            let name: String = chars.by_ref().take_while(|c| *c != '=').collect();
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') || name.starts_with(|c: char| c.is_ascii_digit()) {
+                return Err(format!("Invalid label name: {}", name));
+            }
+            if chars.next() != Some('"') {
+                return Err(format!("Missing quote for label: {}", name));
+            }
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some('\\') => value.push('\\'),
+                        Some('"') => value.push('"'),
+                        Some('n') => value.push('\n'),
+                        other => return Err(format!("Invalid escape in label {}: {:?}", name, other)),
+                    },
+                    Some('"') => break,
+                    Some('\n') | None => return Err(format!("Unterminated value for label: {}", name)),
+                    Some(c) => value.push(c),
+                }
+            }
+            if labels.iter().any(|(other, _): &(String, String)| *other == name) {
+                return Err(format!("Duplicate label: {}", name));
+            }
+            labels.push((name, value));
+            match chars.next() {
+                Some(',') | None => {},
+                Some(c) => return Err(format!("Unexpected character after label value: {}", c)),
+            }
+        }
+        Ok(labels)
+    }
+
+    // Check that the content follows the OpenMetrics 1.0.0 text format, returning the families and their types.
+    fn check_openmetrics(content: &str) -> Result<HashMap<String, String>, String> {
+        let content = content.strip_suffix("# EOF\n").ok_or("Missing or misplaced EOF")?;
+        let mut families: HashMap<String, String> = HashMap::new();
+        let mut family: Option<(String, String)> = None;
+        let mut seen_samples: HashSet<String> = HashSet::new();
+        for line in content.lines() {
+            if let Some(metadata) = line.strip_prefix("# ") {
+                let mut parts = metadata.splitn(3, ' ');
+                let (keyword, name, text) = (parts.next().unwrap(), parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+                if family.as_ref().map(|(family_name, _)| family_name.as_str()) != Some(name) {
+                    if families.contains_key(name) {
+                        return Err(format!("Interleaved metric family: {}", name));
+                    }
+                    family = Some((name.to_owned(), "unknown".to_owned()));
+                    families.insert(name.to_owned(), "unknown".to_owned());
+                }
+                match keyword {
+                    "TYPE" => {
+                        if !["counter", "gauge", "histogram", "info", "stateset", "unknown"].contains(&text) {
+                            return Err(format!("Invalid type for {}: {}", name, text));
+                        }
+                        family.as_mut().unwrap().1 = text.to_owned();
+                        families.insert(name.to_owned(), text.to_owned());
+                    },
+                    "UNIT" => {
+                        if text.is_empty() || !name.ends_with(&format!("_{}", text)) {
+                            return Err(format!("Family {} doesn't end with unit: {}", name, text));
+                        }
+                        if family.as_ref().unwrap().1 == "info" || family.as_ref().unwrap().1 == "stateset" {
+                            return Err(format!("Unit not allowed for family: {}", name));
+                        }
+                    },
+                    "HELP" => {
+                        parse_labels(&format!("help=\"{}\"", text)).map_err(|err| format!("Invalid help for {}: {}", name, err))?;
+                    },
+                    _ => return Err(format!("Invalid metadata line: {}", line)),
+                }
+                continue;
+            }
+
+            let (family_name, type_) = family.as_ref().ok_or(format!("Sample without family: {}", line))?;
+            let (series, value) = line.rsplit_once(' ').ok_or(format!("Missing value: {}", line))?;
+            let (sample_name, labels) = match series.split_once('{') {
+                Some((name, labels_str)) => (name, parse_labels(labels_str.strip_suffix('}').ok_or(format!("Unterminated labels: {}", line))?)?),
+                None => (series, Vec::new()),
+            };
+            let expected_names = match type_.as_str() {
+                "counter" => vec![format!("{}_total", family_name)],
+                "info" => vec![format!("{}_info", family_name)],
+                "histogram" => vec![format!("{}_bucket", family_name), format!("{}_count", family_name), format!("{}_sum", family_name)],
+                _ => vec![family_name.clone()],
+            };
+            if !expected_names.iter().any(|expected_name| expected_name == sample_name) {
+                return Err(format!("Sample name {} doesn't match family {}", sample_name, family_name));
+            }
+            if !["+Inf", "-Inf", "NaN"].contains(&value) && value.parse::<f64>().is_err() {
+                return Err(format!("Invalid value: {}", line));
+            }
+            if type_ == "info" && value != "1" {
+                return Err(format!("Info value must be 1: {}", line));
+            }
+            if type_ == "stateset" && (!["0", "1"].contains(&value) || !labels.iter().any(|(name, _)| name == family_name)) {
+                return Err(format!("Invalid state set sample: {}", line));
+            }
+            if sample_name.ends_with("_bucket") && type_ == "histogram" && !labels.iter().any(|(name, value)| name == "le" && value.parse::<f64>().is_ok()) {
+                return Err(format!("Invalid histogram bucket: {}", line));
+            }
+            if !seen_samples.insert(series.to_owned()) {
+                return Err(format!("Duplicate sample: {}", line));
+            }
+        }
+
+        Ok(families)
+    }
+
+    // Build all UPS metric families for two example UPSes, including the counters from polling mode.
+    fn build_example_families() -> MetricFamilies {
+        let mut vars: VarMap = HashMap::new();
+        vars.insert(UPS_DESCRIPTION_PSEUDOVAR.to_owned(), "Description with \"quotes\", \\ and\nnewline".to_owned());
+        vars.insert(UPS_STATUS_METRIC.nut_var.to_owned(), "OL CHRG".to_owned());
+        for (var, metrics) in VAR_METRICS.iter() {
+            vars.insert((*var).to_owned(), example_var_value(metrics[0].var_transform));
+        }
+        // Leave out the power for one UPS, so it's estimated instead
+        let mut vars_without_power = vars.clone();
+        vars_without_power.retain(|var, _| var != "ups.realpower" && var != "ups.power");
+        let upses: UpsVarMap = HashMap::from([("alpha".to_owned(), vars), ("beta".to_owned(), vars_without_power)]);
+        let ups_labels: UpsLabelMap = HashMap::from([("alpha".to_owned(), vec![("rack".to_owned(), "a\"1\"".to_owned())]), ("beta".to_owned(), Vec::new())]);
+
+        let poll_results = vec![PollResult { target: "nut-server:3493".to_owned(), upses: upses.clone(), nut_version: "2.8.0".to_owned(), ups_labels: ups_labels.clone() }; 2];
+        track_statuses(&poll_results);
+        track_energy(&poll_results);
+        let mut metric_families = build_metric_families(&upses, "2.8.0", &ups_labels);
+        metric_families.append(&mut build_polled_families("nut-server:3493", &ups_labels));
+
+        metric_families
+    }
+
+    #[test]
+    fn openmetrics_output_is_conformant() {
+        let content = build_openmetrics_content(&build_example_families());
+        let families = match check_openmetrics(&content) {
+            Ok(families) => families,
+            Err(err) => panic!("{}\n\n{}", err, content),
+        };
+
+        for metric in METRICS.values().copied().chain([&UPS_STATUS_TRANSITIONS_METRIC, &UPS_STATUS_SECONDS_METRIC, &OUTPUT_ENERGY_METRIC]) {
+            let family = openmetrics_family_name(metric);
+            assert_eq!(families.get(family).map(String::as_str), Some(metric.type_), "Missing or mistyped family: {}", family);
+        }
+    }
+
+    #[test]
+    fn prometheus_output_has_types_and_counter_names() {
+        let content = build_prometheus_content(&build_example_families());
+        assert!(!content.contains("# EOF") && !content.contains("# UNIT"), "{}", content);
+
+        let mut types: HashMap<&str, &str> = HashMap::new();
+        for line in content.lines() {
+            if let Some(metadata) = line.strip_prefix("# TYPE ") {
+                let (name, type_) = metadata.split_once(' ').unwrap();
+                assert!(["counter", "gauge", "histogram"].contains(&type_), "Invalid type for {}: {}", name, type_);
+                assert!(types.insert(name, type_).is_none(), "Duplicate TYPE line for {}", name);
+            } else if !line.starts_with('#') {
+                let name = line.split(['{', ' ']).next().unwrap();
+                assert!(types.contains_key(name), "Sample without TYPE line: {}", line);
+            }
+        }
+
+        for metric in METRICS.values().copied().chain([&UPS_STATUS_TRANSITIONS_METRIC, &UPS_STATUS_SECONDS_METRIC, &OUTPUT_ENERGY_METRIC]) {
+            let expected_type = match metric.type_ {
+                "info" | "stateset" => "gauge",
+                type_ => type_,
+            };
+            assert_eq!(types.get(metric.metric).copied(), Some(expected_type), "Missing or mistyped family: {}", metric.metric);
+            if metric.type_ == "counter" {
+                assert!(metric.metric.ends_with("_total"), "Counter without _total suffix: {}", metric.metric);
+            }
+        }
+    }
+
+    fn build_transform_metric(var_transform: VarTransform) -> Metric {
+        Metric { metric: "nut_test", help: "Test.", type_: "gauge", unit: "", nut_var: "test.var", var_transform, is_integer: false }
+    }
+
+    #[test]
+    fn map_transform_uses_values_and_default() {
+        let metric = build_transform_metric(VarTransform::Map { values: &BEEPER_STATUS_VALUES, default: 0.0 });
+        assert_eq!(transform_var_value("ups", "enabled", &metric), Some(1.0));
+        assert_eq!(transform_var_value("ups", "muted", &metric), Some(3.0));
+        assert_eq!(transform_var_value("ups", "Enabled", &metric), Some(0.0));
+        assert_eq!(transform_var_value("ups", "", &metric), Some(0.0));
+
+        let metric = build_transform_metric(VarTransform::Map { values: &[("on", 1.0), ("off", 0.0)], default: f64::NAN });
+        assert_eq!(transform_var_value("ups", "off", &metric), Some(0.0));
+        assert!(transform_var_value("ups", "unknown", &metric).unwrap().is_nan());
+    }
+
+    #[test]
+    fn linear_transform_scales_and_offsets() {
+        let metric = build_transform_metric(MINUTES_TO_SECONDS);
+        assert_eq!(transform_var_value("ups", "2", &metric), Some(120.0));
+        assert_eq!(transform_var_value("ups", "1.5", &metric), Some(90.0));
+        assert_eq!(transform_var_value("ups", "soon", &metric), None);
+
+        let metric = build_transform_metric(VarTransform::Linear { scale: 5.0 / 9.0, offset: -160.0 / 9.0 });
+        let fahrenheit_to_celsius = |value: &str| transform_var_value("ups", value, &metric).unwrap();
+        assert!(fahrenheit_to_celsius("32").abs() < 1e-9);
+        assert!((fahrenheit_to_celsius("212") - 100.0).abs() < 1e-9);
+        assert!((fahrenheit_to_celsius("-40") + 40.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn state_map_transform_matches_prefixes() {
+        let test_result = |value: &str| {
+            let index = transform_var_value("ups", value, &UPS_TEST_RESULT_METRIC).unwrap();
+            UPS_TEST_RESULT_STATES[index as usize].0
+        };
+        assert_eq!(test_result("Done and passed"), "passed");
+        assert_eq!(test_result(" OK "), "passed");
+        assert_eq!(test_result("Done and warning"), "warning");
+        assert_eq!(test_result("Done and error"), "failed");
+        assert_eq!(test_result("Test failed"), "failed");
+        assert_eq!(test_result("Aborted"), "aborted");
+        assert_eq!(test_result("In progress"), "in_progress");
+        assert_eq!(test_result("No test initiated"), "none");
+        assert_eq!(test_result("Something else"), "unknown");
+        assert_eq!(test_result(""), "unknown");
+        assert_eq!(test_result("   "), "unknown");
+    }
+
+    #[test]
+    fn state_map_transform_builds_a_sample_per_state() {
+        let vars: VarMap = HashMap::from([(UPS_TEST_RESULT_METRIC.nut_var.to_owned(), "Done and warning".to_owned())]);
+        let upses: UpsVarMap = HashMap::from([("alpha".to_owned(), vars)]);
+        let families = build_metric_families(&upses, "2.8.0", &HashMap::new());
+        let (_, samples) = families.iter().find(|(metric, _)| metric.metric == UPS_TEST_RESULT_METRIC.metric).unwrap();
+        let states: Vec<(&str, f64)> = samples.iter().map(|sample| (sample.labels.last().unwrap().1.as_str(), sample.value)).collect();
+        let expected: Vec<(&str, f64)> = UPS_TEST_RESULT_STATES.iter().map(|(state, _)| (*state, if *state == "warning" { 1.0 } else { 0.0 })).collect();
+        assert_eq!(states, expected);
+        assert!(samples.iter().all(|sample| sample.labels.last().unwrap().0 == "result"));
+    }
+
+    #[test]
+    fn nut_dates_are_parsed() {
+        let parse = |value: &str| parse_nut_date("ups", "battery.date", value);
+        // 2021-03-11T00:00:00Z
+        let timestamp = Some(1615420800);
+        // Year, month, day
+        assert_eq!(parse("2021-03-11"), timestamp);
+        assert_eq!(parse("2021/03/11"), timestamp);
+        assert_eq!(parse("2021/3/11"), timestamp);
+        assert_eq!(parse(" 2021-03-11 "), timestamp);
+        // Month, day, year
+        assert_eq!(parse("03/11/2021"), timestamp);
+        assert_eq!(parse("3/11/21"), timestamp);
+        assert_eq!(parse("03/11/99"), Some(921110400));
+        assert_eq!(parse("01/01/70"), Some(0));
+        // Invalid
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("unknown"), None);
+        assert_eq!(parse("2021-13-01"), None);
+        assert_eq!(parse("02/30/2021"), None);
+        assert_eq!(parse("2021-03-11T00:00:00"), None);
+        assert_eq!(parse("11.03.2021"), None);
+        assert_eq!(parse("03-11-2021"), None);
+    }
+
+    #[test]
+    fn self_metrics_output_is_conformant() {
+        record_http_request("/nut", 200, Duration::from_millis(5));
+        record_scrape(&NutTarget::from_address("nut-server:3493".to_owned(), Duration::from_secs(10), 2), Duration::from_millis(3), true);
+
+        let content = build_openmetrics_content(&build_self_metric_families());
+        if let Err(err) = check_openmetrics(&content) {
+            panic!("{}\n\n{}", err, content);
+        }
+    }
+}
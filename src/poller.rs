@@ -0,0 +1,188 @@
+use std::sync::Arc;
+
+use tokio::sync::broadcast::Receiver;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use crate::config::Config;
+use crate::config_reload::{SharedConfig, current_config};
+use crate::energy_tracker::{build_energy_families, load_energy_state, save_energy_state, track_energy};
+use crate::labels::{UpsLabelMap, build_ups_labels};
+use crate::metrics::{NutVersion, UpsVarMap};
+use crate::mqtt_client::MqttClient;
+use crate::nut_client::scrape_nut;
+use crate::push_client::PushClient;
+use crate::openmetrics_builder::MetricFamilies;
+use crate::status_tracker::{build_status_families, track_statuses};
+use crate::webhook_client::WebhookClient;
+
+// Result of a successful scrape of a polled target
+#[derive(Debug, Clone)]
+pub struct PollResult {
+    pub target: String,
+    pub upses: UpsVarMap,
+    pub nut_version: NutVersion,
+    pub ups_labels: UpsLabelMap,
+}
+
+// Poll the configured targets in the background and push the results.
+// The targets are resolved from the current config for each poll, so reloads apply,
+// including named poll targets which are only added by a reload.
+pub async fn run_poller(shared_config: SharedConfig, mut shutdown_channel: Receiver<bool>) {
+    let config = current_config(&shared_config);
+    let push_client = config.push_url.as_ref().map(|push_url| Arc::new(PushClient::new(&config, push_url)));
+    let mqtt_client = config.mqtt_url.as_ref().map(|mqtt_url| MqttClient::new(&config, mqtt_url));
+    let webhook_client = match config.webhook_urls.is_empty() {
+        true => None,
+        false => Some(WebhookClient::new(&config)),
+    };
+    if !config.poll_targets.is_empty() {
+        if push_client.is_none() && mqtt_client.is_none() && webhook_client.is_none() {
+            log::warn!("Polling targets without anywhere to send the results.");
+        }
+        log::info!("Polling {} target(s) every {:?}.", config.poll_targets.len(), config.poll_interval);
+    }
+    if let Some(energy_state_file) = &config.energy_state_file {
+        if let Err(err) = load_energy_state(energy_state_file) {
+            log::error!("{}", err);
+        }
+    }
+
+    let mut interval = tokio::time::interval(config.poll_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut push_task: Option<JoinHandle<()>> = None;
+    loop {
+        let poll_future = async {
+            interval.tick().await;
+            let poll_config = current_config(&shared_config);
+            if poll_config.poll_targets.is_empty() {
+                return;
+            }
+            let results = poll_targets(&poll_config).await;
+            track_statuses(&results);
+            track_energy(&results);
+            if let Some(energy_state_file) = &config.energy_state_file {
+                if let Err(err) = save_energy_state(energy_state_file) {
+                    log::error!("{}", err);
+                }
+            }
+            if let Some(webhook_client) = &webhook_client {
+                webhook_client.notify(&results);
+            }
+            if let Some(mqtt_client) = &mqtt_client {
+                mqtt_client.publish(&results);
+            }
+            // Push in the background, so a slow push endpoint doesn't delay the next poll.
+            // An unfinished push is superseded by the newer results.
+            if let Some(push_client) = &push_client {
+                if let Some(previous_push_task) = push_task.take() {
+                    if !previous_push_task.is_finished() {
+                        log::warn!("Aborting the previous push, which is still running.");
+                        previous_push_task.abort();
+                    }
+                }
+                let push_client = push_client.clone();
+                let targets = poll_config.poll_targets.clone();
+                push_task = Some(tokio::spawn(async move {
+                    push_client.push(&targets, &results).await;
+                }));
+            }
+        };
+        tokio::select! {
+            _ = poll_future => {},
+            _ = shutdown_channel.recv() => {
+                log::debug!("Stopping poller.");
+                break;
+            },
+        }
+    }
+}
+
+// Build the families tracked across polls for a target, if it's polled.
+// Only the UPSes in the label map are included, so a scrape of a single UPS only gets the counters for that UPS.
+pub fn build_polled_families(target: &str, ups_labels: &UpsLabelMap) -> MetricFamilies {
+    let mut families = build_status_families(target, ups_labels);
+    families.append(&mut build_energy_families(target, ups_labels));
+    families
+}
+
+// Scrape all targets concurrently, so a slow target (up to its timeout) doesn't delay the others.
+async fn poll_targets(config: &Config) -> Vec<PollResult> {
+    let scrape_tasks: Vec<_> = config.poll_targets.iter().map(|target_name| {
+        let target = match config.resolve_target(target_name) {
+            Ok(target) => target,
+            Err(err) => {
+                log::warn!("Failed to poll target \"{}\": {}", target_name, err);
+                return None;
+            },
+        };
+        Some(tokio::spawn(async move {
+            let result = scrape_nut(&target).await.map_err(|err| err.to_string());
+            (target, result)
+        }))
+    }).collect();
+
+    let mut results = Vec::new();
+    for (target_name, scrape_task) in config.poll_targets.iter().zip(scrape_tasks) {
+        let scrape_result = match scrape_task {
+            Some(scrape_task) => scrape_task.await,
+            None => continue,
+        };
+        match scrape_result {
+            Ok((target, Ok((upses, nut_version)))) => {
+                let ups_labels = build_ups_labels(config, &target.name, &upses);
+                results.push(PollResult { target: target.name, upses, nut_version, ups_labels });
+            },
+            Ok((_, Err(err))) => log::warn!("Failed to poll target \"{}\": {}", target_name, err),
+            Err(err) => log::warn!("Failed to poll target \"{}\": {}", target_name, err),
+        }
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::config::read_config;
+    use crate::energy_tracker::track_energy;
+    use crate::labels::build_ups_labels;
+    use crate::metrics::{UpsVarMap, VarMap};
+    use crate::openmetrics_builder::build_metric_families;
+    use crate::status_tracker::track_statuses;
+
+    use super::{PollResult, build_polled_families};
+
+    #[test]
+    fn ups_filter_applies_to_polled_families() {
+        let target = "ups-filter-test:3493";
+        let vars: VarMap = HashMap::from([("ups.status".to_owned(), "OL".to_owned()), ("ups.realpower".to_owned(), "100".to_owned())]);
+        let upses: UpsVarMap = HashMap::from([("alpha".to_owned(), vars.clone()), ("beta".to_owned(), vars)]);
+        let config = read_config();
+        let poll_results = vec![PollResult {
+            target: target.to_owned(),
+            upses: upses.clone(),
+            nut_version: "2.8.0".to_owned(),
+            ups_labels: build_ups_labels(&config, target, &upses),
+        }; 2];
+        track_statuses(&poll_results);
+        track_energy(&poll_results);
+
+        // Like the "ups" query parameter of the metrics endpoint
+        let mut filtered_upses = upses;
+        filtered_upses.retain(|ups, _| ups == "alpha");
+        let ups_labels = build_ups_labels(&config, target, &filtered_upses);
+        let mut families = build_metric_families(&filtered_upses, "2.8.0", &ups_labels);
+        let polled_families = build_polled_families(target, &ups_labels);
+        assert!(!polled_families.is_empty());
+        families.extend(polled_families);
+
+        for (metric, samples) in families.iter() {
+            for sample in samples.iter() {
+                let ups = sample.labels.iter().find(|(label, _)| label == "ups").map(|(_, value)| value.as_str());
+                assert_ne!(ups, Some("beta"), "Filtered UPS in {}: {:?}", metric.metric, sample.labels);
+            }
+        }
+    }
+}
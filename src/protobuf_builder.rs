@@ -0,0 +1,244 @@
+use prost::Message;
+
+use crate::openmetrics_builder::{MetricFamilies, Sample, metric_help, prometheus_type};
+
+// Subset of the Prometheus client model ("io.prometheus.client", proto2), for gauges, counters and histograms only.
+// Fields are optional to match proto2 presence, so that zero values are still encoded.
+#[derive(Clone, PartialEq, Message)]
+struct LabelPair {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    value: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Gauge {
+    #[prost(double, optional, tag = "1")]
+    value: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Counter {
+    #[prost(double, optional, tag = "1")]
+    value: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Bucket {
+    #[prost(uint64, optional, tag = "1")]
+    cumulative_count: Option<u64>,
+    #[prost(double, optional, tag = "2")]
+    upper_bound: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Histogram {
+    #[prost(uint64, optional, tag = "1")]
+    sample_count: Option<u64>,
+    #[prost(double, optional, tag = "2")]
+    sample_sum: Option<f64>,
+    #[prost(message, repeated, tag = "3")]
+    bucket: Vec<Bucket>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Metric {
+    #[prost(message, repeated, tag = "1")]
+    label: Vec<LabelPair>,
+    #[prost(message, optional, tag = "2")]
+    gauge: Option<Gauge>,
+    #[prost(message, optional, tag = "3")]
+    counter: Option<Counter>,
+    #[prost(message, optional, tag = "7")]
+    histogram: Option<Histogram>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct MetricFamily {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    help: Option<String>,
+    #[prost(enumeration = "MetricType", optional, tag = "3")]
+    type_: Option<i32>,
+    #[prost(message, repeated, tag = "4")]
+    metric: Vec<Metric>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum MetricType {
+    Counter = 0,
+    Gauge = 1,
+    Histogram = 4,
+}
+
+// Build the length-delimited protobuf format, as used by Prometheus.
+pub fn build_protobuf_content(families: &MetricFamilies) -> Vec<u8> {
+    let mut buffer: Vec<u8> = Vec::new();
+    for (metric, samples) in families.iter() {
+        let (metric_type, metrics) = match prometheus_type(metric) {
+            "histogram" => (MetricType::Histogram, build_histogram_metrics(samples)),
+            type_ => {
+                let is_counter = type_ == "counter";
+                let metrics = samples.iter().map(|sample| Metric {
+                    label: build_label_pairs(&sample.labels),
+                    gauge: match is_counter { true => None, false => Some(Gauge { value: Some(sample.value) }) },
+                    counter: match is_counter { true => Some(Counter { value: Some(sample.value) }), false => None },
+                    histogram: None,
+                }).collect();
+                (match is_counter { true => MetricType::Counter, false => MetricType::Gauge }, metrics)
+            },
+        };
+        let family = MetricFamily {
+            name: Some(metric.metric.to_owned()),
+            help: Some(metric_help(metric)),
+            type_: Some(metric_type as i32),
+            metric: metrics,
+        };
+        // Writing to a vec can't fail
+        family.encode_length_delimited(&mut buffer).unwrap();
+    }
+
+    buffer
+}
+
+fn build_label_pairs(labels: &[(String, String)]) -> Vec<LabelPair> {
+    labels.iter().map(|(name, value)| LabelPair { name: Some(name.clone()), value: Some(value.clone()) }).collect()
+}
+
+// Combine the bucket and sum samples of each histogram series (see Sample).
+// The "+Inf" bucket is implied by the count.
+fn build_histogram_metrics(samples: &[Sample]) -> Vec<Metric> {
+    let mut series: Vec<(&[(String, String)], Histogram)> = Vec::new();
+    for sample in samples.iter() {
+        let (series_labels, bound) = match sample.labels.split_last() {
+            Some(((label, bound), series_labels)) if label == "le" => (series_labels, Some(bound)),
+            _ => (&sample.labels[..], None),
+        };
+        let index = match series.iter().position(|(labels, _)| *labels == series_labels) {
+            Some(index) => index,
+            None => {
+                series.push((series_labels, Histogram::default()));
+                series.len() - 1
+            },
+        };
+        let histogram = &mut series[index].1;
+        match bound.map(|bound| bound.parse::<f64>()) {
+            Some(Ok(bound)) if bound.is_infinite() => histogram.sample_count = Some(sample.value as u64),
+            Some(Ok(bound)) => histogram.bucket.push(Bucket { cumulative_count: Some(sample.value as u64), upper_bound: Some(bound) }),
+            Some(Err(_)) => {},
+            None => histogram.sample_sum = Some(sample.value),
+        }
+    }
+
+    series.into_iter().map(|(labels, histogram)| Metric {
+        label: build_label_pairs(labels),
+        gauge: None,
+        counter: None,
+        histogram: Some(histogram),
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use prost::Message;
+
+    use crate::labels::UpsLabelMap;
+    use crate::metrics::{HTTP_REQUEST_DURATION_METRIC, UpsVarMap, VarMap};
+    use crate::openmetrics_builder::{MetricFamilies, Sample, build_metric_families, prometheus_type};
+    use crate::poller::PollResult;
+    use crate::status_tracker::{build_status_families, track_statuses};
+
+    use super::{MetricFamily, MetricType, build_protobuf_content};
+
+    fn decode_families(content: &[u8]) -> Vec<MetricFamily> {
+        let mut buffer = content;
+        let mut families = Vec::new();
+        while !buffer.is_empty() {
+            families.push(MetricFamily::decode_length_delimited(&mut buffer).unwrap());
+        }
+        families
+    }
+
+    #[test]
+    fn protobuf_output_round_trips() {
+        let vars: VarMap = HashMap::from([("ups.status".to_owned(), "OL".to_owned()), ("battery.charge".to_owned(), "50".to_owned())]);
+        let upses: UpsVarMap = HashMap::from([("alpha".to_owned(), vars)]);
+        let ups_labels: UpsLabelMap = HashMap::from([("alpha".to_owned(), vec![("rack".to_owned(), "a1".to_owned())])]);
+
+        // Include the status counters from polling mode
+        let poll_results = vec![PollResult { target: "protobuf-server:3493".to_owned(), upses: upses.clone(), nut_version: "2.8.0".to_owned(), ups_labels: ups_labels.clone() }; 2];
+        track_statuses(&poll_results);
+        let mut expected_families = build_metric_families(&upses, "2.8.0", &ups_labels);
+        expected_families.append(&mut build_status_families("protobuf-server:3493", &ups_labels));
+
+        let decoded = decode_families(&build_protobuf_content(&expected_families));
+        assert_eq!(decoded.len(), expected_families.len());
+        for ((metric, samples), family) in expected_families.iter().zip(decoded.iter()) {
+            assert_eq!(family.name.as_deref(), Some(metric.metric));
+            let expected_type = match prometheus_type(metric) {
+                "counter" => MetricType::Counter,
+                _ => MetricType::Gauge,
+            };
+            assert_eq!(family.type_, Some(expected_type as i32), "{}", metric.metric);
+            assert_eq!(family.metric.len(), samples.len(), "{}", metric.metric);
+            for (sample, decoded_metric) in samples.iter().zip(family.metric.iter()) {
+                let labels: Vec<(String, String)> = decoded_metric.label.iter()
+                    .map(|label| (label.name.clone().unwrap(), label.value.clone().unwrap()))
+                    .collect();
+                assert_eq!(labels, sample.labels, "{}", metric.metric);
+                // Zero values must still be present
+                let value = match expected_type {
+                    MetricType::Counter => decoded_metric.counter.as_ref().and_then(|counter| counter.value),
+                    _ => decoded_metric.gauge.as_ref().and_then(|gauge| gauge.value),
+                };
+                assert_eq!(value.map(f64::to_bits), Some(sample.value.to_bits()), "{}", metric.metric);
+            }
+        }
+
+        // Statesets are exposed as gauges, with a sample per state
+        let status = decoded.iter().find(|family| family.name.as_deref() == Some("nut_ups_status")).unwrap();
+        assert_eq!(status.type_, Some(MetricType::Gauge as i32));
+        assert!(status.metric.iter().any(|metric| metric.gauge.as_ref().and_then(|gauge| gauge.value) == Some(0.0)));
+        let charge = decoded.iter().find(|family| family.name.as_deref() == Some("nut_battery_charge")).unwrap();
+        assert_eq!(charge.metric[0].gauge.as_ref().and_then(|gauge| gauge.value), Some(0.5));
+        let transitions = decoded.iter().find(|family| family.name.as_deref() == Some("nut_ups_status_transitions_total")).unwrap();
+        assert_eq!(transitions.type_, Some(MetricType::Counter as i32));
+        assert!(transitions.metric.iter().all(|metric| metric.counter.is_some() && metric.gauge.is_none()));
+    }
+    #[test]
+    fn histograms_are_combined() {
+        let build_labels = |labels: &[(&str, &str)]| -> Vec<(String, String)> {
+            labels.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+        };
+        let families: MetricFamilies = vec![
+            (&HTTP_REQUEST_DURATION_METRIC, vec![
+                Sample { labels: build_labels(&[("path", "/metrics"), ("le", "0.1")]), value: 2.0 },
+                Sample { labels: build_labels(&[("path", "/metrics"), ("le", "+Inf")]), value: 3.0 },
+                Sample { labels: build_labels(&[("path", "/metrics")]), value: 0.5 },
+            ]),
+        ];
+        let decoded = decode_families(&build_protobuf_content(&families));
+        assert_eq!(decoded.len(), 1);
+
+        // Bucket and sum samples are combined into a single series, where the "+Inf" bucket is the count
+        let durations = &decoded[0];
+        assert_eq!(durations.name.as_deref(), Some(HTTP_REQUEST_DURATION_METRIC.metric));
+        assert_eq!(durations.type_, Some(MetricType::Histogram as i32));
+        assert_eq!(durations.metric.len(), 1);
+        let labels: Vec<(&str, &str)> = durations.metric[0].label.iter()
+            .map(|label| (label.name.as_deref().unwrap(), label.value.as_deref().unwrap()))
+            .collect();
+        assert_eq!(labels, vec![("path", "/metrics")]);
+        let histogram = durations.metric[0].histogram.as_ref().unwrap();
+        assert_eq!(histogram.sample_count, Some(3));
+        assert_eq!(histogram.sample_sum, Some(0.5));
+        assert_eq!(histogram.bucket.len(), 1);
+        assert_eq!(histogram.bucket[0].cumulative_count, Some(2));
+        assert_eq!(histogram.bucket[0].upper_bound, Some(0.1));
+    }
+}
@@ -0,0 +1,294 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::{Body, Method, Request};
+use prost::Message;
+use url::Url;
+
+use crate::common::ErrorResult;
+use crate::config::{Config, PushFormat};
+use crate::http_client::{HttpClient, build_http_client, send_with_retries, split_url_credentials};
+use crate::metrics::{Metric, PUSH_FAILURES_METRIC};
+use crate::openmetrics_builder::{MetricFamilies, Sample, build_metric_families, build_prometheus_content, name_samples};
+use crate::poller::{PollResult, build_polled_families};
+
+const CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+const CONTENT_TYPE_PROTOBUF: &str = "application/x-protobuf";
+const REMOTE_WRITE_VERSION: &str = "0.1.0";
+
+// Number of pushes which failed after all retries
+static PUSH_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+// Prometheus remote write messages ("prometheus", proto3)
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<RemoteSample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct RemoteSample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+pub struct PushClient {
+    client: HttpClient,
+    url: Url,
+    authorization: Option<String>,
+    format: PushFormat,
+    job: String,
+    retries: u32,
+}
+
+impl PushClient {
+    pub fn new(config: &Config, push_url: &Url) -> PushClient {
+        // Use credentials from the URL for basic auth, but don't send them in the URL
+        let (url, authorization) = split_url_credentials(push_url);
+
+        PushClient {
+            client: build_http_client(),
+            url,
+            authorization,
+            format: config.push_format,
+            job: config.push_job.clone(),
+            retries: config.push_retries,
+        }
+    }
+
+    // Push the results of a poll of the targets, counting failures.
+    pub async fn push(&self, targets: &[String], results: &[PollResult]) {
+        let result = match self.format {
+            PushFormat::Pushgateway => self.push_pushgateway(targets, results).await,
+            PushFormat::RemoteWrite => self.push_remote_write(results).await,
+        };
+        if let Err(err) = result {
+            PUSH_FAILURES.fetch_add(1, Ordering::Relaxed);
+            log::error!("Failed to push metrics: {}", err);
+        }
+    }
+
+    // Push to the Pushgateway, using one group per target (replacing previous metrics for the target).
+    // The groups of targets which failed to be polled are deleted, instead of keeping the last metrics forever.
+    async fn push_pushgateway(&self, targets: &[String], results: &[PollResult]) -> ErrorResult<()> {
+        for result in results.iter() {
+            let mut families = build_metric_families(&result.upses, &result.nut_version, &result.ups_labels);
+            families.append(&mut build_polled_families(&result.target, &result.ups_labels));
+            families.push(build_push_failures_family());
+            let content = build_prometheus_content(&families).into_bytes();
+            let url = self.build_group_url(&result.target)?;
+            self.send(Method::PUT, &url, CONTENT_TYPE_PROMETHEUS, None, content).await?;
+        }
+        for target in targets.iter().filter(|target| !results.iter().any(|result| result.target == **target)) {
+            let url = self.build_group_url(target)?;
+            self.send(Method::DELETE, &url, CONTENT_TYPE_PROMETHEUS, None, Vec::new()).await?;
+        }
+
+        Ok(())
+    }
+
+    fn build_group_url(&self, target: &str) -> ErrorResult<Url> {
+        let mut url = self.url.clone();
+        match url.path_segments_mut() {
+            Ok(mut segments) => {
+                segments.pop_if_empty().push("metrics");
+                segments.extend(build_grouping_key_segments("job", &self.job));
+                segments.extend(build_grouping_key_segments("instance", target));
+            },
+            Err(_) => return Err(format!("Invalid push URL: {}", self.url).into()),
+        }
+        Ok(url)
+    }
+
+    // Push all targets to a Prometheus remote write endpoint in a single request.
+    async fn push_remote_write(&self, results: &[PollResult]) -> ErrorResult<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_millis() as i64);
+        let mut write_request = WriteRequest { timeseries: Vec::new() };
+        for result in results.iter() {
+            let mut families = build_metric_families(&result.upses, &result.nut_version, &result.ups_labels);
+            families.append(&mut build_polled_families(&result.target, &result.ups_labels));
+            self.add_remote_write_series(&mut write_request, &families, Some(&result.target), timestamp);
+        }
+        self.add_remote_write_series(&mut write_request, &vec![build_push_failures_family()], None, timestamp);
+
+        let content = match snap::raw::Encoder::new().compress_vec(&write_request.encode_to_vec()) {
+            Ok(content) => content,
+            Err(err) => return Err(format!("Failed to compress remote write request: {}", err).into()),
+        };
+        let url = self.url.clone();
+        self.send(Method::POST, &url, CONTENT_TYPE_PROTOBUF, Some("snappy"), content).await
+    }
+
+    fn add_remote_write_series(&self, write_request: &mut WriteRequest, families: &MetricFamilies, instance: Option<&str>, timestamp: i64) {
+        for (metric, samples) in families.iter() {
+            for sample in samples.iter().flat_map(|sample| name_samples(metric, sample)) {
+                let mut labels = vec![
+                    Label { name: "__name__".to_owned(), value: sample.name.clone() },
+                    Label { name: "job".to_owned(), value: self.job.clone() },
+                ];
+                if let Some(instance) = instance {
+                    labels.push(Label { name: "instance".to_owned(), value: instance.to_owned() });
+                }
+                labels.extend(sample.labels.iter().map(|(name, value)| Label { name: name.clone(), value: value.clone() }));
+                // Labels must be sorted by name and unique, where the labels added here take precedence
+                labels.sort_by(|a, b| a.name.cmp(&b.name));
+                labels.dedup_by(|a, b| a.name == b.name);
+                write_request.timeseries.push(TimeSeries { labels, samples: vec![RemoteSample { value: sample.value, timestamp }] });
+            }
+        }
+    }
+
+    async fn send(&self, method: Method, url: &Url, content_type: &str, content_encoding: Option<&str>, content: Vec<u8>) -> ErrorResult<()> {
+        let build_request = || {
+            let mut request_builder = Request::builder().method(method.clone()).uri(url.as_str()).header("Content-Type", content_type);
+            if let Some(content_encoding) = content_encoding {
+                request_builder = request_builder.header("Content-Encoding", content_encoding).header("X-Prometheus-Remote-Write-Version", REMOTE_WRITE_VERSION);
+            }
+            if let Some(authorization) = &self.authorization {
+                request_builder = request_builder.header("Authorization", authorization);
+            }
+            request_builder.body(Body::from(content.clone()))
+        };
+        send_with_retries(&self.client, build_request, self.retries, &format!("Push to \"{}\"", url)).await
+    }
+}
+
+// Build the path segments for a Pushgateway grouping key label.
+// Values which can't be a path segment use the base64 form (e.g. "instance@base64/<value>").
+fn build_grouping_key_segments(name: &str, value: &str) -> [String; 2] {
+    match value.is_empty() || value.contains('/') {
+        true => [format!("{}@base64", name), match value.is_empty() { true => "=".to_owned(), false => base64::encode_config(value, base64::URL_SAFE) }],
+        false => [name.to_owned(), value.to_owned()],
+    }
+}
+
+pub fn build_push_failures_family() -> (&'static Metric, Vec<Sample>) {
+    (&PUSH_FAILURES_METRIC, vec![Sample { labels: Vec::new(), value: PUSH_FAILURES.load(Ordering::Relaxed) as f64 }])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, Server};
+    use prost::Message;
+    use url::Url;
+
+    use crate::config::{PushFormat, read_config};
+    use crate::labels::UpsLabelMap;
+    use crate::metrics::{UpsVarMap, VarMap};
+    use crate::poller::PollResult;
+
+    use super::{PushClient, WriteRequest};
+
+    type ReceivedRequests = Arc<Mutex<Vec<(Method, String, Vec<u8>)>>>;
+
+    // Start a local stand-in for the push endpoint, recording the method, path and body of each request.
+    fn start_push_server() -> (SocketAddr, ReceivedRequests) {
+        let received: ReceivedRequests = Arc::new(Mutex::new(Vec::new()));
+        let service_received = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let received = service_received.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let received = received.clone();
+                    async move {
+                        let method = request.method().clone();
+                        let path = request.uri().path().to_owned();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap().to_vec();
+                        received.lock().unwrap().push((method, path, body));
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (address, received)
+    }
+
+    fn build_poll_results(target: &str) -> Vec<PollResult> {
+        let vars: VarMap = HashMap::from([("ups.status".to_owned(), "OL".to_owned()), ("battery.charge".to_owned(), "100".to_owned())]);
+        let upses: UpsVarMap = HashMap::from([("alpha".to_owned(), vars)]);
+        let ups_labels: UpsLabelMap = HashMap::from([("alpha".to_owned(), vec![("rack".to_owned(), "a1".to_owned()), ("zone".to_owned(), "z".to_owned())])]);
+        vec![PollResult { target: target.to_owned(), upses, nut_version: "2.8.0".to_owned(), ups_labels }]
+    }
+
+    fn build_push_client(address: SocketAddr, push_format: PushFormat) -> PushClient {
+        let mut config = read_config();
+        config.push_format = push_format;
+        config.push_retries = 0;
+        PushClient::new(&config, &Url::parse(&format!("http://{}/", address)).unwrap())
+    }
+
+    #[tokio::test]
+    async fn remote_write_labels_are_sorted_and_unique() {
+        let (address, received) = start_push_server();
+        build_push_client(address, PushFormat::RemoteWrite).push(&["nut-server:3493".to_owned()], &build_poll_results("nut-server:3493")).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let content = snap::raw::Decoder::new().decompress_vec(&received[0].2).unwrap();
+        let write_request = WriteRequest::decode(content.as_slice()).unwrap();
+        assert!(!write_request.timeseries.is_empty());
+        for series in write_request.timeseries.iter() {
+            let names: Vec<&str> = series.labels.iter().map(|label| label.name.as_str()).collect();
+            assert!(names.windows(2).all(|pair| pair[0] < pair[1]), "Labels not sorted and unique: {:?}", names);
+            assert!(names.contains(&"__name__") && names.contains(&"job"), "{:?}", names);
+        }
+        let charge_series = write_request.timeseries.iter()
+            .find(|series| series.labels.iter().any(|label| label.name == "__name__" && label.value == "nut_battery_charge"))
+            .unwrap();
+        let labels: Vec<(&str, &str)> = charge_series.labels.iter().map(|label| (label.name.as_str(), label.value.as_str())).collect();
+        assert_eq!(labels, vec![("__name__", "nut_battery_charge"), ("instance", "nut-server:3493"), ("job", "nut"), ("rack", "a1"), ("ups", "alpha"), ("zone", "z")]);
+    }
+
+    #[tokio::test]
+    async fn pushgateway_grouping_key_is_encoded() {
+        let (address, received) = start_push_server();
+        let push_client = build_push_client(address, PushFormat::Pushgateway);
+        push_client.push(&["nut-server:3493".to_owned()], &build_poll_results("nut-server:3493")).await;
+        push_client.push(&["site/a".to_owned()], &build_poll_results("site/a")).await;
+
+        let received = received.lock().unwrap();
+        let paths: Vec<&str> = received.iter().map(|(_, path, _)| path.as_str()).collect();
+        assert_eq!(paths, vec!["/metrics/job/nut/instance/nut-server:3493", "/metrics/job/nut/instance@base64/c2l0ZS9h"]);
+    }
+
+    #[tokio::test]
+    async fn pushgateway_groups_of_failed_targets_are_deleted() {
+        let (address, received) = start_push_server();
+        let targets = vec!["nut-server:3493".to_owned(), "failed-server:3493".to_owned()];
+        build_push_client(address, PushFormat::Pushgateway).push(&targets, &build_poll_results("nut-server:3493")).await;
+
+        let received = received.lock().unwrap();
+        let requests: Vec<(&Method, &str, bool)> = received.iter().map(|(method, path, body)| (method, path.as_str(), body.is_empty())).collect();
+        assert_eq!(requests, vec![
+            (&Method::PUT, "/metrics/job/nut/instance/nut-server:3493", false),
+            (&Method::DELETE, "/metrics/job/nut/instance/failed-server:3493", true),
+        ]);
+    }
+}
@@ -0,0 +1,26 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    // Token bucket for all rate limited requests: available tokens and when last updated
+    static ref RATE_LIMIT_BUCKET: Mutex<Option<(f64, Instant)>> = Mutex::new(None);
+}
+
+// Take a token from the bucket, which is refilled at "rate" tokens per second and holds up to one second worth (at least one).
+// If there are no tokens, return how long until there is one.
+pub fn take_rate_limit_token(rate: f64) -> Result<(), Duration> {
+    let capacity = rate.max(1f64);
+    let now = Instant::now();
+    let mut bucket = RATE_LIMIT_BUCKET.lock().unwrap();
+    let (tokens, last_update) = bucket.unwrap_or((capacity, now));
+    let tokens = (tokens + now.duration_since(last_update).as_secs_f64() * rate).min(capacity);
+    if tokens >= 1f64 {
+        *bucket = Some((tokens - 1f64, now));
+        Ok(())
+    } else {
+        *bucket = Some((tokens, now));
+        Err(Duration::from_secs_f64((1f64 - tokens) / rate))
+    }
+}
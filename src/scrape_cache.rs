@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::common::ErrorResult;
+use crate::metrics::{NutVersion, SCRAPE_CACHE_HITS_METRIC, SCRAPE_CACHE_MISSES_METRIC, UpsVarMap};
+use crate::nut_client::scrape_nut;
+use crate::openmetrics_builder::{MetricFamilies, Sample};
+use crate::targets::NutTarget;
+
+// A successful scrape and when it was scraped
+type CachedScrape = (Instant, (UpsVarMap, NutVersion));
+
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    // Cached scrapes per target (see NutTarget::scrape_key)
+    static ref SCRAPE_CACHE: Mutex<HashMap<Vec<u8>, CachedScrape>> = Mutex::new(HashMap::new());
+}
+
+// Scrape a target, using the cached result if younger than the TTL.
+// Bypassing the cache still updates it. Failed scrapes are not cached.
+pub async fn scrape_nut_cached(target: &NutTarget, ttl: Duration, bypass_cache: bool) -> ErrorResult<(UpsVarMap, NutVersion)> {
+    let scrape_key = target.scrape_key();
+    if !bypass_cache {
+        let scrape_cache = SCRAPE_CACHE.lock().unwrap();
+        if let Some((scrape_time, result)) = scrape_cache.get(&scrape_key) {
+            if scrape_time.elapsed() < ttl {
+                CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+                return Ok(result.clone());
+            }
+        }
+        CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let result = scrape_nut(target).await?;
+    let mut scrape_cache = SCRAPE_CACHE.lock().unwrap();
+    scrape_cache.retain(|_, (scrape_time, _)| scrape_time.elapsed() < ttl);
+    scrape_cache.insert(scrape_key, (Instant::now(), result.clone()));
+    Ok(result)
+}
+
+// Drop the cached scrapes on a config reload, so the new config applies to the next scrape.
+pub fn clear_scrape_cache() {
+    SCRAPE_CACHE.lock().unwrap().clear();
+}
+
+pub fn build_scrape_cache_families() -> MetricFamilies {
+    vec![
+        (&SCRAPE_CACHE_HITS_METRIC, vec![Sample { labels: Vec::new(), value: CACHE_HITS.load(Ordering::Relaxed) as f64 }]),
+        (&SCRAPE_CACHE_MISSES_METRIC, vec![Sample { labels: Vec::new(), value: CACHE_MISSES.load(Ordering::Relaxed) as f64 }]),
+    ]
+}
@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::config_reload::build_config_reload_family;
+use crate::meta::APP_VERSION;
+use crate::metrics::{EXPORTER_INFO_METRIC, HTTP_REQUESTS_METRIC, HTTP_REQUEST_DURATION_METRIC, Metric, PROCESS_CPU_METRIC, PROCESS_OPEN_FDS_METRIC, PROCESS_RESIDENT_MEMORY_METRIC, PROCESS_START_TIME_METRIC, SCRAPES_METRIC, SCRAPE_DURATION_METRIC, SCRAPE_ERRORS_METRIC};
+use crate::openmetrics_builder::{MetricFamilies, Sample, format_bucket_bound};
+use crate::push_client::build_push_failures_family;
+use crate::scrape_cache::build_scrape_cache_families;
+use crate::targets::NutTarget;
+
+// Clock ticks per second used in /proc (USER_HZ), which is 100 on practically all Linux systems
+const PROC_TICKS_PER_SECOND: f64 = 100.0;
+// Upper bounds of the HTTP request duration histogram buckets, in seconds
+const HTTP_REQUEST_DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+// Target label for scrapes of targets which aren't known (see NutTarget), to limit the cardinality
+const OTHER_TARGET_LABEL: &str = "other";
+
+// Count and total duration of a type of request
+#[derive(Default)]
+struct RequestCounter {
+    count: u64,
+    errors: u64,
+    seconds: f64,
+    last_success: Option<Instant>,
+    // Cumulative counts for HTTP_REQUEST_DURATION_BUCKETS, for HTTP requests only
+    duration_buckets: [u64; HTTP_REQUEST_DURATION_BUCKETS.len()],
+}
+
+lazy_static! {
+    // HTTP request counters per path and status
+    static ref HTTP_REQUESTS: Mutex<BTreeMap<(String, u16), RequestCounter>> = Mutex::new(BTreeMap::new());
+    // Scrape counters per target
+    static ref SCRAPES: Mutex<BTreeMap<String, RequestCounter>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn record_http_request(path: &str, status: u16, duration: Duration) {
+    let mut http_requests = HTTP_REQUESTS.lock().unwrap();
+    let counter = http_requests.entry((path.to_owned(), status)).or_default();
+    counter.count += 1;
+    counter.seconds += duration.as_secs_f64();
+    for (bucket, bound) in counter.duration_buckets.iter_mut().zip(HTTP_REQUEST_DURATION_BUCKETS) {
+        if duration.as_secs_f64() <= bound {
+            *bucket += 1;
+        }
+    }
+}
+
+pub fn record_scrape(target: &NutTarget, duration: Duration, is_error: bool) {
+    let target_label = match target.is_known {
+        true => target.name.as_str(),
+        false => OTHER_TARGET_LABEL,
+    };
+    let mut scrapes = SCRAPES.lock().unwrap();
+    let counter = scrapes.entry(target_label.to_owned()).or_default();
+    counter.count += 1;
+    if is_error {
+        counter.errors += 1;
+    } else {
+        counter.last_success = Some(Instant::now());
+    }
+    counter.seconds += duration.as_secs_f64();
+}
+
+// Get the time since the last successful scrape of any of the targets, or of any known target if none are specified.
+pub fn time_since_successful_scrape(targets: &[String]) -> Option<Duration> {
+    let scrapes = SCRAPES.lock().unwrap();
+    scrapes.iter()
+        .filter(|(target, _)| match targets.is_empty() {
+            true => *target != OTHER_TARGET_LABEL,
+            false => targets.contains(target),
+        })
+        .filter_map(|(_, counter)| counter.last_success)
+        .max()
+        .map(|last_success| last_success.elapsed())
+}
+
+// Build the metrics about the exporter itself.
+pub fn build_self_metric_families() -> MetricFamilies {
+    let mut families: MetricFamilies = vec![
+        (&EXPORTER_INFO_METRIC, vec![Sample { labels: vec![("version".to_owned(), APP_VERSION.to_owned())], value: 1f64 }]),
+    ];
+
+    let mut add_family = |metric: &'static Metric, samples: Vec<Sample>| {
+        if !samples.is_empty() {
+            families.push((metric, samples));
+        }
+    };
+
+    // Process
+    let process_stats = read_process_stats();
+    let process_samples = |value: Option<f64>| value.map(|value| vec![Sample { labels: Vec::new(), value }]).unwrap_or_default();
+    add_family(&PROCESS_CPU_METRIC, process_samples(process_stats.cpu_seconds));
+    add_family(&PROCESS_RESIDENT_MEMORY_METRIC, process_samples(process_stats.resident_memory_bytes));
+    add_family(&PROCESS_OPEN_FDS_METRIC, process_samples(process_stats.open_fds));
+    add_family(&PROCESS_START_TIME_METRIC, process_samples(process_stats.start_time_seconds));
+
+    // HTTP requests
+    {
+        let http_requests = HTTP_REQUESTS.lock().unwrap();
+        let labels = |path: &str, status: u16| vec![("path".to_owned(), path.to_owned()), ("status".to_owned(), status.to_string())];
+        add_family(&HTTP_REQUESTS_METRIC, http_requests.iter().map(|((path, status), counter)| Sample { labels: labels(path, *status), value: counter.count as f64 }).collect());
+        add_family(&HTTP_REQUEST_DURATION_METRIC, http_requests.iter().flat_map(|((path, status), counter)| {
+            let bucket_sample = |bound: f64, value: u64| {
+                let mut bucket_labels = labels(path, *status);
+                bucket_labels.push(("le".to_owned(), format_bucket_bound(bound)));
+                Sample { labels: bucket_labels, value: value as f64 }
+            };
+            let mut samples: Vec<Sample> = HTTP_REQUEST_DURATION_BUCKETS.iter().zip(counter.duration_buckets).map(|(bound, value)| bucket_sample(*bound, value)).collect();
+            samples.push(bucket_sample(f64::INFINITY, counter.count));
+            samples.push(Sample { labels: labels(path, *status), value: counter.seconds });
+            samples
+        }).collect());
+    }
+
+    // Scrapes
+    {
+        let scrapes = SCRAPES.lock().unwrap();
+        let labels = |target: &str| vec![("target".to_owned(), target.to_owned())];
+        add_family(&SCRAPES_METRIC, scrapes.iter().map(|(target, counter)| Sample { labels: labels(target), value: counter.count as f64 }).collect());
+        add_family(&SCRAPE_ERRORS_METRIC, scrapes.iter().map(|(target, counter)| Sample { labels: labels(target), value: counter.errors as f64 }).collect());
+        add_family(&SCRAPE_DURATION_METRIC, scrapes.iter().map(|(target, counter)| Sample { labels: labels(target), value: counter.seconds }).collect());
+    }
+
+    families.push(build_push_failures_family());
+    families.append(&mut build_scrape_cache_families());
+    families.push(build_config_reload_family());
+    families
+}
+
+// Process stats from /proc, which are missing on other platforms.
+#[derive(Default)]
+struct ProcessStats {
+    cpu_seconds: Option<f64>,
+    resident_memory_bytes: Option<f64>,
+    open_fds: Option<f64>,
+    start_time_seconds: Option<f64>,
+}
+
+fn read_process_stats() -> ProcessStats {
+    let mut stats = ProcessStats::default();
+
+    // Fields after the command (which may contain spaces), starting with field 3 (state)
+    if let Ok(stat) = std::fs::read_to_string("/proc/self/stat") {
+        let fields: Vec<&str> = stat.rsplit_once(')').map(|(_, fields)| fields.split_whitespace().collect()).unwrap_or_default();
+        let field = |number: usize| fields.get(number - 3).and_then(|value| value.parse::<f64>().ok());
+        if let (Some(utime), Some(stime)) = (field(14), field(15)) {
+            stats.cpu_seconds = Some((utime + stime) / PROC_TICKS_PER_SECOND);
+        }
+        if let (Some(start_ticks), Some(boot_time)) = (field(22), read_boot_time()) {
+            stats.start_time_seconds = Some(boot_time + start_ticks / PROC_TICKS_PER_SECOND);
+        }
+    }
+    if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+        stats.resident_memory_bytes = status.lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse::<f64>().ok())
+            .map(|kilobytes| kilobytes * 1024.0);
+    }
+    if let Ok(entries) = std::fs::read_dir("/proc/self/fd") {
+        stats.open_fds = Some(entries.count() as f64);
+    }
+
+    stats
+}
+
+fn read_boot_time() -> Option<f64> {
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    stat.lines().find_map(|line| line.strip_prefix("btime ")).and_then(|value| value.trim().parse::<f64>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::metrics::{HTTP_REQUEST_DURATION_METRIC, SCRAPES_METRIC};
+    use crate::openmetrics_builder::MetricFamilies;
+    use crate::targets::NutTarget;
+
+    use super::{build_self_metric_families, record_http_request, record_scrape};
+
+    fn find_sample_value(families: &MetricFamilies, metric: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        let (_, samples) = families.iter().find(|(family_metric, _)| family_metric.metric == metric)?;
+        samples.iter()
+            .find(|sample| sample.labels.len() == labels.len() && labels.iter().all(|(name, value)| sample.labels.contains(&((*name).to_owned(), (*value).to_owned()))))
+            .map(|sample| sample.value)
+    }
+
+    #[test]
+    fn unknown_targets_share_a_label() {
+        let mut known_target = NutTarget::from_address("known-target-test:3493".to_owned(), Duration::from_secs(10), 2);
+        known_target.is_known = true;
+        record_scrape(&known_target, Duration::from_millis(3), false);
+        for i in 0..3 {
+            let unknown_target = NutTarget::from_address(format!("junk-{}.invalid:3493", i), Duration::from_secs(10), 2);
+            record_scrape(&unknown_target, Duration::from_millis(3), true);
+        }
+
+        let families = build_self_metric_families();
+        let (_, samples) = families.iter().find(|(metric, _)| metric.metric == SCRAPES_METRIC.metric).unwrap();
+        assert!(samples.iter().all(|sample| !sample.labels[0].1.contains("invalid")), "{:?}", samples);
+        assert_eq!(find_sample_value(&families, SCRAPES_METRIC.metric, &[("target", "known-target-test:3493")]), Some(1f64));
+        assert!(find_sample_value(&families, SCRAPES_METRIC.metric, &[("target", "other")]).unwrap() >= 3f64);
+    }
+
+    #[test]
+    fn http_request_durations_are_a_histogram() {
+        let path = "/histogram-test";
+        record_http_request(path, 200, Duration::from_millis(20));
+        record_http_request(path, 200, Duration::from_millis(200));
+        record_http_request(path, 200, Duration::from_secs(20));
+
+        let families = build_self_metric_families();
+        let metric = HTTP_REQUEST_DURATION_METRIC.metric;
+        let bucket = |le: &str| find_sample_value(&families, metric, &[("path", path), ("status", "200"), ("le", le)]);
+        assert_eq!(bucket("0.01"), Some(0f64));
+        assert_eq!(bucket("0.025"), Some(1f64));
+        assert_eq!(bucket("0.25"), Some(2f64));
+        assert_eq!(bucket("10.0"), Some(2f64));
+        assert_eq!(bucket("+Inf"), Some(3f64));
+        let sum = find_sample_value(&families, metric, &[("path", path), ("status", "200")]).unwrap();
+        assert!((sum - 20.22).abs() < 1e-9, "{}", sum);
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use tokio::sync::broadcast::Receiver;
+use tokio::time::MissedTickBehavior;
+
+use crate::common::ErrorResult;
+use crate::config::Config;
+use crate::config_reload::{SharedConfig, current_config};
+use crate::labels::build_static_labels;
+use crate::nut_client::list_nut_upses;
+
+// Prometheus file_sd/http_sd target group
+#[derive(Debug, Serialize)]
+struct TargetGroup {
+    targets: Vec<String>,
+    labels: BTreeMap<String, String>,
+}
+
+// Build the Prometheus file_sd/http_sd JSON for the configured targets (named and poll targets),
+// optionally with one entry per UPS, which is passed to the exporter as the "ups" param.
+// The entries set the "target" param and a distinct "instance" label ("<target>/<ups>" for UPS entries) themselves,
+// so only the exporter address needs to be relabeled.
+// Labels from the config are already added to the metrics, so they're only added as meta labels (for relabeling).
+pub async fn build_sd_content(config: &Config, include_upses: bool) -> String {
+    let mut target_names: Vec<String> = config.targets.keys().cloned().collect();
+    for poll_target in config.poll_targets.iter() {
+        if !target_names.contains(poll_target) {
+            target_names.push(poll_target.clone());
+        }
+    }
+
+    // List the UPSes of all targets concurrently
+    let ups_tasks: Vec<_> = target_names.iter().map(|target_name| {
+        let target = config.resolve_target(target_name).ok().filter(|_| include_upses)?;
+        Some(tokio::spawn(async move {
+            list_nut_upses(&target).await.map_err(|err| err.to_string())
+        }))
+    }).collect();
+
+    let mut groups = Vec::new();
+    for (target_name, ups_task) in target_names.iter().zip(ups_tasks) {
+        let ups_result = match ups_task {
+            Some(ups_task) => ups_task.await.unwrap_or_else(|err| Err(err.to_string())),
+            None => Ok(Vec::new()),
+        };
+        let upses = match ups_result {
+            Ok(upses) if !upses.is_empty() => upses,
+            Ok(_) => {
+                groups.push(build_target_group(config, target_name, None));
+                continue;
+            },
+            Err(err) => {
+                // Keep the target, so it's still scraped (and shown as failing)
+                log::warn!("Failed to list UPSes for service discovery of target \"{}\": {}", target_name, err);
+                groups.push(build_target_group(config, target_name, None));
+                continue;
+            },
+        };
+        for ups in upses.iter() {
+            groups.push(build_target_group(config, target_name, Some(ups)));
+        }
+    }
+
+    serde_json::to_string_pretty(&groups).unwrap()
+}
+
+fn build_target_group(config: &Config, target: &str, ups: Option<&str>) -> TargetGroup {
+    let mut labels: BTreeMap<String, String> = build_static_labels(config, target, ups).into_iter()
+        .map(|(label, value)| (format!("__meta_nut_label_{}", label), value))
+        .collect();
+    labels.insert("__meta_nut_target".to_owned(), target.to_owned());
+    labels.insert("__param_target".to_owned(), target.to_owned());
+    match ups {
+        Some(ups) => {
+            labels.insert("__meta_nut_ups".to_owned(), ups.to_owned());
+            labels.insert("__param_ups".to_owned(), ups.to_owned());
+            labels.insert("instance".to_owned(), format!("{}/{}", target, ups));
+        },
+        None => {
+            labels.insert("instance".to_owned(), target.to_owned());
+        },
+    }
+
+    TargetGroup { targets: vec![target.to_owned()], labels }
+}
+
+// Periodically write the service discovery JSON to the configured file, if any.
+pub async fn run_sd_file_writer(shared_config: SharedConfig, mut shutdown_channel: Receiver<bool>) {
+    let config = current_config(&shared_config);
+    let sd_file = match &config.sd_file {
+        Some(sd_file) => sd_file.clone(),
+        None => return,
+    };
+    log::info!("Writing service discovery file \"{}\" every {:?}.", sd_file, config.sd_file_interval);
+
+    let mut interval = tokio::time::interval(config.sd_file_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut previous_content = String::new();
+    loop {
+        let write_future = async {
+            interval.tick().await;
+            let content = build_sd_content(&current_config(&shared_config), config.sd_file_ups).await;
+            // Only write changes, to avoid needless reloads in Prometheus
+            if content != previous_content {
+                match write_sd_file(&sd_file, &content) {
+                    Ok(()) => previous_content = content,
+                    Err(err) => log::error!("{}", err),
+                }
+            }
+        };
+        tokio::select! {
+            _ = write_future => {},
+            _ = shutdown_channel.recv() => {
+                log::debug!("Stopping service discovery file writer.");
+                break;
+            },
+        }
+    }
+}
+
+// Replace the file atomically, so Prometheus never reads a partial file.
+fn write_sd_file(path: &str, content: &str) -> ErrorResult<()> {
+    let temp_path = format!("{}.tmp", path);
+    if let Err(err) = std::fs::write(&temp_path, content).and_then(|_| std::fs::rename(&temp_path, path)) {
+        return Err(format!("Failed to write service discovery file \"{}\": {}", path, err).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::read_config;
+
+    use super::build_target_group;
+
+    #[test]
+    fn ups_groups_have_distinct_instances() {
+        let config = read_config();
+        let target_group = build_target_group(&config, "nut-server:3493", None);
+        let alpha_group = build_target_group(&config, "nut-server:3493", Some("alpha"));
+        let beta_group = build_target_group(&config, "nut-server:3493", Some("beta"));
+
+        assert_eq!(target_group.labels["instance"], "nut-server:3493");
+        assert_eq!(alpha_group.labels["instance"], "nut-server:3493/alpha");
+        assert_eq!(beta_group.labels["instance"], "nut-server:3493/beta");
+        assert_eq!(alpha_group.labels["__param_ups"], "alpha");
+        assert_eq!(beta_group.labels["__param_ups"], "beta");
+        assert!(!target_group.labels.contains_key("__param_ups"));
+        for group in [target_group, alpha_group, beta_group] {
+            assert_eq!(group.targets, vec!["nut-server:3493"]);
+            assert_eq!(group.labels["__param_target"], "nut-server:3493");
+        }
+    }
+}
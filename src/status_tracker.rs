@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+
+use crate::labels::UpsLabelMap;
+use crate::metrics::{UPS_STATUS_ELEMENTS, UPS_STATUS_METRIC, UPS_STATUS_SECONDS_METRIC, UPS_STATUS_TRANSITIONS_METRIC};
+use crate::openmetrics_builder::{MetricFamilies, Sample, build_ups_labels};
+use crate::poller::PollResult;
+
+// Status counters for a single UPS, tracked across polls
+struct UpsStatusCounters {
+    // Statuses set at the last poll
+    statuses: HashSet<&'static str>,
+    // Time of the last poll, if the statuses have been known since then
+    last_poll: Option<Instant>,
+    // Counters for each status in UPS_STATUS_ELEMENTS
+    transitions: [u64; UPS_STATUS_ELEMENTS.len()],
+    seconds: [f64; UPS_STATUS_ELEMENTS.len()],
+}
+
+lazy_static! {
+    // Status counters per target and UPS
+    static ref STATUS_COUNTERS: Mutex<HashMap<String, BTreeMap<String, UpsStatusCounters>>> = Mutex::new(HashMap::new());
+}
+
+// Update the status counters from a poll.
+// The time since the last poll is attributed to the statuses seen at the last poll.
+// Targets and UPSes missing from the poll (e.g. failed) or without status start over at the next poll,
+// so the time between failed polls is not counted.
+pub fn track_statuses(results: &[PollResult]) {
+    let now = Instant::now();
+    let mut status_counters = STATUS_COUNTERS.lock().unwrap();
+    for (target, target_counters) in status_counters.iter_mut() {
+        let upses = results.iter().find(|result| result.target == *target).map(|result| &result.upses);
+        for (ups, counters) in target_counters.iter_mut() {
+            if !matches!(upses, Some(upses) if upses.contains_key(ups)) {
+                counters.last_poll = None;
+            }
+        }
+    }
+    for result in results.iter() {
+        let target_counters = status_counters.entry(result.target.clone()).or_default();
+        for (ups, vars) in result.upses.iter() {
+            let statuses = match vars.get(UPS_STATUS_METRIC.nut_var) {
+                Some(status_raw) => parse_statuses(status_raw),
+                None => {
+                    if let Some(counters) = target_counters.get_mut(ups) {
+                        counters.last_poll = None;
+                    }
+                    continue;
+                },
+            };
+            let counters = match target_counters.get_mut(ups) {
+                Some(counters) => counters,
+                None => {
+                    // Nothing to compare with for the first poll
+                    target_counters.insert(ups.clone(), UpsStatusCounters {
+                        statuses,
+                        last_poll: Some(now),
+                        transitions: [0; UPS_STATUS_ELEMENTS.len()],
+                        seconds: [0f64; UPS_STATUS_ELEMENTS.len()],
+                    });
+                    continue;
+                },
+            };
+
+            let elapsed = counters.last_poll.map_or(0f64, |last_poll| now.duration_since(last_poll).as_secs_f64());
+            for (i, status) in UPS_STATUS_ELEMENTS.iter().enumerate() {
+                if counters.statuses.contains(status) {
+                    counters.seconds[i] += elapsed;
+                } else if statuses.contains(status) {
+                    counters.transitions[i] += 1;
+                }
+            }
+            counters.statuses = statuses;
+            counters.last_poll = Some(now);
+        }
+    }
+}
+
+// Build the status counter families for a target, if it's polled, for the UPSes in the label map (i.e. the scraped UPSes).
+// The time since the last poll is included for the current statuses, so the counters don't lag behind.
+pub fn build_status_families(target: &str, ups_labels: &UpsLabelMap) -> MetricFamilies {
+    let now = Instant::now();
+    let status_counters = STATUS_COUNTERS.lock().unwrap();
+    let target_counters = match status_counters.get(target) {
+        Some(target_counters) => target_counters,
+        None => return Vec::new(),
+    };
+
+    let mut transitions_samples: Vec<Sample> = Vec::new();
+    let mut seconds_samples: Vec<Sample> = Vec::new();
+    for (ups, counters) in target_counters.iter().filter(|(ups, _)| ups_labels.contains_key(*ups)) {
+        let labels = build_ups_labels(ups, ups_labels);
+        let elapsed = counters.last_poll.map_or(0f64, |last_poll| now.duration_since(last_poll).as_secs_f64());
+        for (i, status) in UPS_STATUS_ELEMENTS.iter().enumerate() {
+            let mut status_labels = labels.clone();
+            status_labels.push(("status".to_owned(), (*status).to_owned()));
+            let seconds = match counters.statuses.contains(status) {
+                true => counters.seconds[i] + elapsed,
+                false => counters.seconds[i],
+            };
+            transitions_samples.push(Sample { labels: status_labels.clone(), value: counters.transitions[i] as f64 });
+            seconds_samples.push(Sample { labels: status_labels, value: seconds });
+        }
+    }
+
+    vec![
+        (&UPS_STATUS_TRANSITIONS_METRIC, transitions_samples),
+        (&UPS_STATUS_SECONDS_METRIC, seconds_samples),
+    ]
+}
+
+fn parse_statuses(status_raw: &str) -> HashSet<&'static str> {
+    let statuses: HashSet<&str> = status_raw.split_whitespace().collect();
+    UPS_STATUS_ELEMENTS.iter().copied().filter(|status| statuses.contains(status)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use crate::labels::UpsLabelMap;
+    use crate::metrics::{UpsVarMap, VarMap};
+    use crate::poller::PollResult;
+
+    use super::{build_status_families, track_statuses};
+
+    fn build_poll_result(target: &str) -> PollResult {
+        let vars: VarMap = HashMap::from([("ups.status".to_owned(), "OL".to_owned())]);
+        let upses: UpsVarMap = HashMap::from([("alpha".to_owned(), vars)]);
+        let ups_labels: UpsLabelMap = HashMap::from([("alpha".to_owned(), Vec::new())]);
+        PollResult { target: target.to_owned(), upses, nut_version: "2.8.0".to_owned(), ups_labels }
+    }
+
+    fn online_seconds(target: &str) -> f64 {
+        let ups_labels: UpsLabelMap = HashMap::from([("alpha".to_owned(), Vec::new())]);
+        build_status_families(target, &ups_labels)[1].1.iter()
+            .find(|sample| sample.labels.contains(&("status".to_owned(), "OL".to_owned())))
+            .unwrap()
+            .value
+    }
+
+    #[test]
+    fn status_seconds_are_counted_between_polls() {
+        let results = vec![build_poll_result("status-test-ok:3493")];
+        track_statuses(&results);
+        std::thread::sleep(Duration::from_millis(20));
+        track_statuses(&results);
+        assert!(online_seconds("status-test-ok:3493") >= 0.02);
+    }
+
+    #[test]
+    fn status_seconds_are_not_counted_over_failed_polls() {
+        let result = build_poll_result("status-test-failed:3493");
+        let other_result = build_poll_result("status-test-other:3493");
+        track_statuses(&[result.clone(), other_result.clone()]);
+        // The target failed, so it's missing from the results
+        track_statuses(&[other_result]);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(online_seconds("status-test-failed:3493"), 0f64);
+        track_statuses(std::slice::from_ref(&result));
+        assert!(online_seconds("status-test-failed:3493") < 0.02);
+    }
+}
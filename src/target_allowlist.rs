@@ -0,0 +1,297 @@
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+use crate::common::ErrorResult;
+
+// Which targets may be scraped through the HTTP endpoints.
+// Targets configured in the exporter itself are always allowed.
+#[derive(Debug, Clone, Default)]
+pub struct TargetAllowlist {
+    pub networks: Vec<Network>,
+    // Lowercase, optionally with a leading "*." wildcard
+    pub hosts: Vec<String>,
+    // Inclusive ranges
+    pub ports: Vec<(u16, u16)>,
+    pub named_targets_only: bool,
+}
+
+// Structure of the allowlist in the YAML config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetAllowlistFile {
+    networks: Vec<String>,
+    hosts: Vec<String>,
+    ports: Vec<PortRangeFile>,
+    named_targets_only: bool,
+}
+
+// A port (e.g. 3493) or an inclusive port range (e.g. "3493-3499")
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PortRangeFile {
+    Port(u16),
+    Range(String),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Network {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl TargetAllowlist {
+    pub fn from_file(file: TargetAllowlistFile) -> ErrorResult<TargetAllowlist> {
+        let mut allowlist = TargetAllowlist {
+            named_targets_only: file.named_targets_only,
+            ..TargetAllowlist::default()
+        };
+        for network_raw in file.networks.iter() {
+            allowlist.networks.push(parse_network(network_raw)?);
+        }
+        for host in file.hosts.iter() {
+            let host = host.trim().to_lowercase();
+            if host.is_empty() || host.contains(':') || host.trim_start_matches("*.").contains('*') {
+                return Err(format!("Invalid allowed host in config file: {}", host).into());
+            }
+            allowlist.hosts.push(host);
+        }
+        for port_range in file.ports.iter() {
+            match port_range {
+                PortRangeFile::Port(port) => allowlist.ports.push((*port, *port)),
+                PortRangeFile::Range(port_range_raw) => allowlist.ports.push(parse_port_range(port_range_raw)?),
+            }
+        }
+
+        Ok(allowlist)
+    }
+
+    // Check a parsed target ("host:port").
+    // Hostnames are matched as given, not resolved, so networks only match IP address targets.
+    pub fn is_allowed(&self, target: &str) -> bool {
+        if self.named_targets_only {
+            return false;
+        }
+        let (host, port) = match split_target(target) {
+            Some(host_port) => host_port,
+            None => return false,
+        };
+
+        let is_host_allowed = if self.networks.is_empty() && self.hosts.is_empty() {
+            true
+        } else if let Ok(address) = host.parse::<IpAddr>() {
+            self.networks.iter().any(|network| network.contains(address))
+        } else {
+            let host = host.to_lowercase();
+            self.hosts.iter().any(|allowed_host| match allowed_host.strip_prefix('*') {
+                Some(suffix) => host.ends_with(suffix) && host.len() > suffix.len(),
+                None => host == *allowed_host,
+            })
+        };
+        let is_port_allowed = self.ports.is_empty() || self.ports.iter().any(|(first, last)| *first <= port && port <= *last);
+
+        is_host_allowed && is_port_allowed
+    }
+
+    // If only some hosts are allowed, as opposed to e.g. only restricting the ports.
+    pub fn restricts_hosts(&self) -> bool {
+        self.named_targets_only || !self.networks.is_empty() || !self.hosts.is_empty()
+    }
+}
+
+impl Network {
+    fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, normalize_address(address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => prefix_matches(&network.octets(), &address.octets(), self.prefix_length),
+            (IpAddr::V6(network), IpAddr::V6(address)) => prefix_matches(&network.octets(), &address.octets(), self.prefix_length),
+            _ => false,
+        }
+    }
+}
+
+// Split a parsed target into the host (without IPv6 brackets) and port.
+fn split_target(target: &str) -> Option<(&str, u16)> {
+    let (host, port) = target.rsplit_once(':')?;
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    Some((host, port.parse::<u16>().ok()?))
+}
+
+// Treat IPv4-mapped IPv6 addresses as IPv4 addresses.
+fn normalize_address(address: IpAddr) -> IpAddr {
+    if let IpAddr::V6(address_v6) = address {
+        let octets = address_v6.octets();
+        if octets[..10].iter().all(|octet| *octet == 0) && octets[10] == 0xff && octets[11] == 0xff {
+            return IpAddr::from([octets[12], octets[13], octets[14], octets[15]]);
+        }
+    }
+    address
+}
+
+fn prefix_matches(network: &[u8], address: &[u8], prefix_length: u8) -> bool {
+    let full_bytes = (prefix_length / 8) as usize;
+    let remaining_bits = prefix_length % 8;
+    if network[..full_bytes] != address[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == address[full_bytes] & mask
+}
+
+// Parse a CIDR network (e.g. "10.0.0.0/8") or a single address.
+fn parse_network(network_raw: &str) -> ErrorResult<Network> {
+    let error = || format!("Invalid allowed network in config file: {}", network_raw);
+    let (address_raw, prefix_length_raw) = match network_raw.trim().split_once('/') {
+        Some((address_raw, prefix_length_raw)) => (address_raw, Some(prefix_length_raw)),
+        None => (network_raw.trim(), None),
+    };
+    let address = match address_raw.parse::<IpAddr>() {
+        Ok(address) => address,
+        Err(_) => return Err(error().into()),
+    };
+    let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+    let prefix_length = match prefix_length_raw.map(str::parse::<u8>) {
+        Some(Ok(prefix_length)) if prefix_length <= max_prefix_length => prefix_length,
+        Some(_) => return Err(error().into()),
+        None => max_prefix_length,
+    };
+
+    Ok(Network { address, prefix_length })
+}
+
+// Parse a port (e.g. "3493") or an inclusive port range (e.g. "3493-3499").
+fn parse_port_range(port_range_raw: &str) -> ErrorResult<(u16, u16)> {
+    let (first_raw, last_raw) = port_range_raw.trim().split_once('-').unwrap_or((port_range_raw.trim(), port_range_raw.trim()));
+    match (first_raw.trim().parse::<u16>(), last_raw.trim().parse::<u16>()) {
+        (Ok(first), Ok(last)) if first <= last => Ok((first, last)),
+        _ => Err(format!("Invalid allowed port range in config file: {}", port_range_raw).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{PortRangeFile, TargetAllowlist, TargetAllowlistFile, parse_network, prefix_matches};
+
+    fn build_allowlist(networks: &[&str], hosts: &[&str], ports: Vec<PortRangeFile>) -> TargetAllowlist {
+        TargetAllowlist::from_file(TargetAllowlistFile {
+            networks: networks.iter().map(|network| (*network).to_owned()).collect(),
+            hosts: hosts.iter().map(|host| (*host).to_owned()).collect(),
+            ports,
+            named_targets_only: false,
+        }).unwrap()
+    }
+
+    #[test]
+    fn prefixes_match_at_the_edges() {
+        let address = |address: &str| -> Vec<u8> {
+            match address.parse::<IpAddr>().unwrap() {
+                IpAddr::V4(address) => address.octets().to_vec(),
+                IpAddr::V6(address) => address.octets().to_vec(),
+            }
+        };
+        // Network, prefix length, address, expected
+        let cases = [
+            ("0.0.0.0", 0, "255.255.255.255", true),
+            ("10.1.2.3", 32, "10.1.2.3", true),
+            ("10.1.2.3", 32, "10.1.2.2", false),
+            ("10.1.2.0", 23, "10.1.3.255", true),
+            ("10.1.2.0", 23, "10.1.1.255", false),
+            ("10.1.2.0", 23, "10.1.4.0", false),
+            ("::", 0, "ffff::1", true),
+            ("2001:db8::1", 128, "2001:db8::1", true),
+            ("2001:db8::1", 128, "2001:db8::", false),
+            ("2001:db8:0:8::", 61, "2001:db8:0:f:ffff::", true),
+            ("2001:db8:0:8::", 61, "2001:db8:0:7:ffff::", false),
+            ("2001:db8:0:8::", 61, "2001:db8:0:10::", false),
+        ];
+        for (network, prefix_length, other, expected) in cases {
+            assert_eq!(prefix_matches(&address(network), &address(other), prefix_length), expected, "{}/{} {}", network, prefix_length, other);
+        }
+    }
+
+    #[test]
+    fn networks_are_parsed_and_matched() {
+        assert!(parse_network("10.0.0.0/33").is_err());
+        assert!(parse_network("2001:db8::/129").is_err());
+        assert!(parse_network("10.0.0.0/x").is_err());
+        assert!(parse_network("nut-server/8").is_err());
+
+        let allowlist = build_allowlist(&["10.1.2.0/23", "2001:db8::/32"], &[], Vec::new());
+        // Target, expected
+        let cases = [
+            ("10.1.3.4:3493", true),
+            ("10.1.4.1:3493", false),
+            ("[2001:db8::5]:3493", true),
+            ("[2001:db9::5]:3493", false),
+            // IPv4-mapped IPv6 addresses match IPv4 networks
+            ("[::ffff:10.1.2.3]:3493", true),
+            ("[::ffff:10.1.4.3]:3493", false),
+            // Hostnames aren't resolved, so they don't match networks
+            ("localhost:3493", false),
+            ("10.1.3.4", false),
+        ];
+        for (target, expected) in cases {
+            assert_eq!(allowlist.is_allowed(target), expected, "{}", target);
+        }
+    }
+
+    #[test]
+    fn wildcard_hosts_only_match_subdomains() {
+        let allowlist = build_allowlist(&[], &["*.example.com", "NUT.example.net"], Vec::new());
+        // Target, expected
+        let cases = [
+            ("ups.example.com:3493", true),
+            ("a.b.example.com:3493", true),
+            ("UPS.Example.COM:3493", true),
+            ("example.com:3493", false),
+            ("evil-example.com:3493", false),
+            ("example.com.evil.net:3493", false),
+            ("nut.example.net:3493", true),
+            ("other.nut.example.net:3493", false),
+            ("10.0.0.1:3493", false),
+        ];
+        for (target, expected) in cases {
+            assert_eq!(allowlist.is_allowed(target), expected, "{}", target);
+        }
+
+        for invalid_host in ["", "nut.example.com:3493", "nut.*.example.com", "*"] {
+            let file = TargetAllowlistFile { hosts: vec![invalid_host.to_owned()], ..TargetAllowlistFile::default() };
+            assert!(TargetAllowlist::from_file(file).is_err(), "{}", invalid_host);
+        }
+    }
+
+    #[test]
+    fn port_ranges_are_inclusive() {
+        let allowlist = build_allowlist(&[], &[], vec![PortRangeFile::Port(3493), PortRangeFile::Range("4000-4010".to_owned())]);
+        // Target, expected
+        let cases = [
+            ("nut:3493", true),
+            ("nut:3492", false),
+            ("nut:3494", false),
+            ("nut:3999", false),
+            ("nut:4000", true),
+            ("nut:4010", true),
+            ("nut:4011", false),
+        ];
+        for (target, expected) in cases {
+            assert_eq!(allowlist.is_allowed(target), expected, "{}", target);
+        }
+
+        for invalid_range in ["4010-4000", "4000-", "-4000", "4000-70000", "ssh"] {
+            let file = TargetAllowlistFile { ports: vec![PortRangeFile::Range(invalid_range.to_owned())], ..TargetAllowlistFile::default() };
+            assert!(TargetAllowlist::from_file(file).is_err(), "{}", invalid_range);
+        }
+    }
+
+    #[test]
+    fn named_targets_only_allows_no_addresses() {
+        let file = TargetAllowlistFile { named_targets_only: true, ..TargetAllowlistFile::default() };
+        let allowlist = TargetAllowlist::from_file(file).unwrap();
+        assert!(!allowlist.is_allowed("10.0.0.1:3493"));
+        assert!(!allowlist.is_allowed("nut:3493"));
+    }
+}
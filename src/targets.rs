@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use regex::Regex;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use serde::Deserialize;
+
+use crate::common::ErrorResult;
+use crate::config::{check_label_name, compile_anchored_regex, parse_target_address};
+use crate::web_config::load_root_certs;
+
+// Shown instead of passwords in debug output
+const REDACTED: &str = "<redacted>";
+
+// A NUT server to scrape and how to connect to it
+#[derive(Clone)]
+pub struct NutTarget {
+    // The name for named targets, else the address, used to identify the target in metrics and logs
+    pub name: String,
+    // "host:port"
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<NutTlsConfig>,
+    pub timeout: Duration,
+    // Max concurrent connections to the address
+    pub max_connections: usize,
+    // Only include matching UPSes
+    pub ups_filter: Option<Regex>,
+    pub labels: BTreeMap<String, String>,
+    // Configured in the exporter or allowlisted, else the target came from a client and isn't used as a self-metric label
+    pub is_known: bool,
+}
+
+// STARTTLS settings for a target
+#[derive(Clone)]
+pub struct NutTlsConfig {
+    pub server_name: ServerName,
+    pub client_config: Arc<ClientConfig>,
+}
+
+// Structure of a named target in the YAML config file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NutTargetFile {
+    address: String,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+    password_file: Option<PathBuf>,
+    tls: Option<NutTlsConfigFile>,
+    timeout: Option<f64>,
+    max_connections: Option<usize>,
+    ups: Option<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NutTlsConfigFile {
+    ca_file: Option<PathBuf>,
+    server_name: Option<String>,
+    insecure_skip_verify: bool,
+}
+
+impl NutTarget {
+    // An unnamed target with default settings, from a parsed address
+    pub fn from_address(address: String, timeout: Duration, max_connections: usize) -> NutTarget {
+        NutTarget {
+            name: address.clone(),
+            address,
+            username: None,
+            password: None,
+            tls: None,
+            timeout,
+            max_connections,
+            ups_filter: None,
+            labels: BTreeMap::new(),
+            is_known: false,
+        }
+    }
+
+    pub fn from_file(name: &str, file: NutTargetFile, default_timeout: Duration, default_max_connections: usize) -> ErrorResult<NutTarget> {
+        let error = |err: &dyn fmt::Display| format!("Invalid target \"{}\" in config file: {}", name, err);
+
+        let address_raw = match file.port {
+            Some(port) => format!("{}:{}", file.address, port),
+            None => file.address.clone(),
+        };
+        let address = parse_target_address(&address_raw).map_err(|err| error(&err))?;
+        let password = match (file.password, &file.password_file) {
+            (Some(_), Some(_)) => return Err(error(&"Both password and password_file are set.").into()),
+            (Some(password), None) => Some(password),
+            (None, Some(password_file)) => match std::fs::read_to_string(password_file) {
+                Ok(password) => Some(password.trim_end_matches(&['\r', '\n'][..]).to_owned()),
+                Err(err) => return Err(error(&format!("Failed to read password file \"{}\": {}", password_file.display(), err)).into()),
+            },
+            (None, None) => None,
+        };
+        if password.is_some() && file.username.is_none() {
+            return Err(error(&"A password requires a username.").into());
+        }
+        let tls = file.tls.as_ref().map(|tls| build_tls_config(&address, tls)).transpose().map_err(|err| error(&err))?;
+        let timeout = match file.timeout {
+            Some(timeout) if timeout > 0f64 => Duration::from_secs_f64(timeout),
+            Some(_) => return Err(error(&"The timeout must be positive.").into()),
+            None => default_timeout,
+        };
+        let max_connections = match file.max_connections {
+            Some(0) => return Err(error(&"The max_connections must be positive.").into()),
+            Some(max_connections) => max_connections,
+            None => default_max_connections,
+        };
+        let ups_filter = file.ups.as_deref().map(compile_anchored_regex).transpose()?;
+        for label in file.labels.keys() {
+            check_label_name(label)?;
+        }
+
+        Ok(NutTarget {
+            name: name.to_owned(),
+            address,
+            username: file.username,
+            password,
+            tls,
+            timeout,
+            max_connections,
+            ups_filter,
+            labels: file.labels,
+            is_known: true,
+        })
+    }
+
+    // Identifies the settings which affect the scrape result (the address, credentials, TLS and UPS filter),
+    // so scrapes are only shared and cached between identical targets. Hashed, so it doesn't contain the password.
+    pub fn scrape_key(&self) -> Vec<u8> {
+        let tls_server_name = self.tls.as_ref().map(|tls| format!("{:?}", tls.server_name));
+        let ups_filter = self.ups_filter.as_ref().map(|ups_filter| ups_filter.as_str());
+        let settings = format!("{:?}", (&self.name, &self.address, &self.username, &self.password, tls_server_name, ups_filter));
+        ring::digest::digest(&ring::digest::SHA256, settings.as_bytes()).as_ref().to_vec()
+    }
+}
+
+// The password is redacted, so it can't end up in logs or error messages
+impl fmt::Debug for NutTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NutTarget")
+            .field("name", &self.name)
+            .field("address", &self.address)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| REDACTED))
+            .field("tls", &self.tls)
+            .field("timeout", &self.timeout)
+            .field("max_connections", &self.max_connections)
+            .field("ups_filter", &self.ups_filter)
+            .field("labels", &self.labels)
+            .field("is_known", &self.is_known)
+            .finish()
+    }
+}
+
+impl fmt::Debug for NutTargetFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NutTargetFile")
+            .field("address", &self.address)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| REDACTED))
+            .field("password_file", &self.password_file)
+            .field("tls", &self.tls)
+            .field("timeout", &self.timeout)
+            .field("max_connections", &self.max_connections)
+            .field("ups", &self.ups)
+            .field("labels", &self.labels)
+            .finish()
+    }
+}
+
+impl fmt::Debug for NutTlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NutTlsConfig").field("server_name", &self.server_name).finish_non_exhaustive()
+    }
+}
+
+fn build_tls_config(address: &str, file: &NutTlsConfigFile) -> ErrorResult<NutTlsConfig> {
+    // Default to the host of the address, without the port and IPv6 brackets
+    let host = address.rsplit_once(':').map(|(host, _)| host).unwrap_or(address);
+    let server_name_raw = match &file.server_name {
+        Some(server_name) => server_name.as_str(),
+        None => host.trim_start_matches('[').trim_end_matches(']'),
+    };
+    let server_name = match ServerName::try_from(server_name_raw) {
+        Ok(server_name) => server_name,
+        Err(_) => return Err(format!("Invalid TLS server name: {}", server_name_raw).into()),
+    };
+
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let client_config = if file.insecure_skip_verify {
+        builder.with_custom_certificate_verifier(Arc::new(NoCertificateVerification)).with_no_client_auth()
+    } else {
+        let roots = match &file.ca_file {
+            Some(ca_file) => load_root_certs(ca_file)?,
+            None => {
+                let mut roots = RootCertStore::empty();
+                roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+                }));
+                roots
+            },
+        };
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+
+    Ok(NutTlsConfig { server_name, client_config: Arc::new(client_config) })
+}
+
+// For "insecure_skip_verify", e.g. for self-signed certificates
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(&self, _end_entity: &Certificate, _intermediates: &[Certificate], _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>, _ocsp_response: &[u8], _now: SystemTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use regex::Regex;
+
+    use super::{NutTarget, NutTargetFile};
+
+    #[test]
+    fn scrape_key_depends_on_scrape_settings() {
+        let target = NutTarget::from_address("scrape-key-test:3493".to_owned(), Duration::from_secs(10), 2);
+        let mut other_timeout = target.clone();
+        other_timeout.timeout = Duration::from_secs(5);
+        assert_eq!(target.scrape_key(), other_timeout.scrape_key());
+
+        let mut other_password = target.clone();
+        other_password.username = Some("monuser".to_owned());
+        other_password.password = Some("secret".to_owned());
+        assert_ne!(target.scrape_key(), other_password.scrape_key());
+        let mut other_filter = target.clone();
+        other_filter.ups_filter = Some(Regex::new("^alpha$").unwrap());
+        assert_ne!(target.scrape_key(), other_filter.scrape_key());
+        let mut other_address = target.clone();
+        other_address.address = "scrape-key-test:3494".to_owned();
+        assert_ne!(target.scrape_key(), other_address.scrape_key());
+    }
+
+    #[test]
+    fn debug_output_redacts_passwords() {
+        let mut target = NutTarget::from_address("debug-test:3493".to_owned(), Duration::from_secs(10), 2);
+        target.username = Some("monuser".to_owned());
+        target.password = Some("hunter2".to_owned());
+        let target_debug = format!("{:?}", target);
+        assert!(target_debug.contains("monuser") && target_debug.contains("<redacted>"), "{}", target_debug);
+        assert!(!target_debug.contains("hunter2"), "{}", target_debug);
+
+        let file: NutTargetFile = serde_yaml::from_str("{ address: localhost, username: monuser, password: hunter2 }").unwrap();
+        let file_debug = format!("{:?}", file);
+        assert!(file_debug.contains("monuser") && file_debug.contains("<redacted>"), "{}", file_debug);
+        assert!(!file_debug.contains("hunter2"), "{}", file_debug);
+    }
+}
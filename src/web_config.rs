@@ -0,0 +1,394 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use hyper::header::HeaderValue;
+use lazy_static::lazy_static;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use serde::Deserialize;
+use tokio_rustls::TlsAcceptor;
+
+use crate::common::ErrorResult;
+use crate::config::Config;
+
+// Hash to verify against for unknown users, so the response time doesn't reveal which users exist
+const DUMMY_PASSWORD_HASH: &str = "$2b$10$XQDDRtBK7nM88fUF7.8zCOisIaRxyCw/83.qLXTbCPxY2X9d65xey";
+// How often to check if the certificate files changed
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// Normally one entry per user, but bcrypt ignores password bytes after the first 72
+const MAX_CACHED_CREDENTIALS: usize = 1000;
+
+lazy_static! {
+    // SHA-256 of the username, password hash and password for credentials which passed the bcrypt check
+    static ref VALID_CREDENTIALS: Mutex<HashSet<Vec<u8>>> = Mutex::new(HashSet::new());
+}
+
+// Web config file, a subset of the Prometheus exporter-toolkit format.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebConfig {
+    pub tls_server_config: Option<TlsServerConfig>,
+    // Username and bcrypt password hash
+    pub basic_auth_users: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsServerConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    #[serde(default)]
+    pub client_auth_type: ClientAuthType,
+    pub client_ca_file: Option<PathBuf>,
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+pub enum ClientAuthType {
+    #[default]
+    NoClientCert,
+    VerifyClientCertIfGiven,
+    RequireAndVerifyClientCert,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+pub enum TlsVersion {
+    TLS12,
+    TLS13,
+}
+
+// Read the web config file (if any) into the config.
+// Relative paths are relative to the web config file.
+pub fn read_web_config_file(config: &mut Config) -> ErrorResult<()> {
+    let path = match &config.web_config_file {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(()),
+    };
+
+    log::debug!("Reading web config file: {}", path.display());
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) => return Err(format!("Failed to read web config file \"{}\": {}", path.display(), err).into()),
+    };
+    let mut web_config: WebConfig = match serde_yaml::from_str(&content) {
+        Ok(web_config) => web_config,
+        Err(err) => return Err(format!("Failed to parse web config file \"{}\": {}", path.display(), err).into()),
+    };
+
+    for (username, hash) in web_config.basic_auth_users.iter() {
+        if hash.parse::<bcrypt::HashParts>().is_err() {
+            return Err(format!("Invalid bcrypt password hash for user \"{}\".", username).into());
+        }
+    }
+    if let Some(tls_server_config) = &mut web_config.tls_server_config {
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        tls_server_config.cert_file = base_dir.join(&tls_server_config.cert_file);
+        tls_server_config.key_file = base_dir.join(&tls_server_config.key_file);
+        tls_server_config.client_ca_file = tls_server_config.client_ca_file.as_ref().map(|client_ca_file| base_dir.join(client_ca_file));
+        // Fail early for invalid TLS configs
+        build_tls_acceptor(tls_server_config)?;
+    }
+
+    config.web_config = web_config;
+
+    Ok(())
+}
+
+pub fn build_tls_acceptor(tls_server_config: &TlsServerConfig) -> ErrorResult<TlsAcceptor> {
+    let min_version = tls_server_config.min_version.unwrap_or(TlsVersion::TLS12);
+    let max_version = tls_server_config.max_version.unwrap_or(TlsVersion::TLS13);
+    let mut versions: Vec<&'static rustls::SupportedProtocolVersion> = Vec::new();
+    if min_version <= TlsVersion::TLS13 && TlsVersion::TLS13 <= max_version {
+        versions.push(&rustls::version::TLS13);
+    }
+    if min_version <= TlsVersion::TLS12 && TlsVersion::TLS12 <= max_version {
+        versions.push(&rustls::version::TLS12);
+    }
+    if versions.is_empty() {
+        return Err("The TLS min_version is higher than the max_version.".into());
+    }
+
+    let builder = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&versions)?;
+    let builder = match (tls_server_config.client_auth_type, &tls_server_config.client_ca_file) {
+        (ClientAuthType::NoClientCert, _) => builder.with_no_client_auth(),
+        (_, None) => return Err("The TLS client_ca_file is required for verifying client certificates.".into()),
+        (ClientAuthType::VerifyClientCertIfGiven, Some(client_ca_file)) => builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(load_root_certs(client_ca_file)?)),
+        (ClientAuthType::RequireAndVerifyClientCert, Some(client_ca_file)) => builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_root_certs(client_ca_file)?)),
+    };
+    let resolver = ReloadingCertResolver::new(&tls_server_config.cert_file, &tls_server_config.key_file)?;
+    let mut server_config = builder.with_cert_resolver(Arc::new(resolver));
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+// Check the basic auth header against the users, if any users are configured.
+pub async fn check_basic_auth(users: &BTreeMap<String, String>, authorization: Option<&HeaderValue>) -> bool {
+    if users.is_empty() {
+        return true;
+    }
+    let (username, password) = match authorization.and_then(parse_basic_auth) {
+        Some(credentials) => credentials,
+        None => return false,
+    };
+
+    let is_known_user = users.contains_key(&username);
+    let hash = users.get(&username).map(String::as_str).unwrap_or(DUMMY_PASSWORD_HASH).to_owned();
+    // Including the hash means changed passwords don't match old entries
+    let credentials_key = build_credentials_key(&username, &hash, &password);
+    if is_known_user && VALID_CREDENTIALS.lock().unwrap().contains(&credentials_key) {
+        return true;
+    }
+
+    // Hashing is slow by design, so keep it off the async workers
+    let is_valid_password = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false)).await.unwrap_or(false);
+    if is_known_user && is_valid_password {
+        let mut valid_credentials = VALID_CREDENTIALS.lock().unwrap();
+        if valid_credentials.len() >= MAX_CACHED_CREDENTIALS {
+            valid_credentials.clear();
+        }
+        valid_credentials.insert(credentials_key);
+    }
+    is_known_user && is_valid_password
+}
+
+// Forget all checked credentials, e.g. when the users change.
+pub fn clear_basic_auth_cache() {
+    VALID_CREDENTIALS.lock().unwrap().clear();
+}
+
+// Neither the username nor the hash contain colons, so the password is the rest.
+fn build_credentials_key(username: &str, hash: &str, password: &str) -> Vec<u8> {
+    let credentials = format!("{}:{}:{}", username, hash, password);
+    ring::digest::digest(&ring::digest::SHA256, credentials.as_bytes()).as_ref().to_vec()
+}
+
+fn parse_basic_auth(authorization: &HeaderValue) -> Option<(String, String)> {
+    let encoded = authorization.to_str().ok()?.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_owned(), password.to_owned()))
+}
+
+// Modification times of the certificate and key files
+type FileTimes = (Option<SystemTime>, Option<SystemTime>);
+
+// Resolves to the configured certificate, reloading it when the files change.
+// Handshakes only read the loaded certificate, the files are checked in the background.
+struct ReloadingCertResolver {
+    cert_files: Arc<CertFiles>,
+    last_check: Mutex<Instant>,
+}
+
+struct CertFiles {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    // Modification times of the loaded files and the loaded certificate
+    loaded: RwLock<(FileTimes, Arc<CertifiedKey>)>,
+}
+
+impl ReloadingCertResolver {
+    fn new(cert_file: &Path, key_file: &Path) -> ErrorResult<ReloadingCertResolver> {
+        let modified = (file_modified(cert_file), file_modified(key_file));
+        let certified_key = load_certified_key(cert_file, key_file)?;
+        let cert_files = CertFiles {
+            cert_file: cert_file.to_owned(),
+            key_file: key_file.to_owned(),
+            loaded: RwLock::new((modified, Arc::new(certified_key))),
+        };
+        Ok(ReloadingCertResolver {
+            cert_files: Arc::new(cert_files),
+            last_check: Mutex::new(Instant::now()),
+        })
+    }
+
+    // Check if the files should be checked again, at most once per interval.
+    fn is_check_due(&self) -> bool {
+        // Skip if another handshake is already checking
+        let mut last_check = match self.last_check.try_lock() {
+            Ok(last_check) => last_check,
+            Err(_) => return false,
+        };
+        if last_check.elapsed() < CERT_CHECK_INTERVAL {
+            return false;
+        }
+        *last_check = Instant::now();
+        true
+    }
+}
+
+impl CertFiles {
+    fn reload_if_changed(&self) {
+        let modified = (file_modified(&self.cert_file), file_modified(&self.key_file));
+        if modified == self.loaded.read().unwrap().0 {
+            return;
+        }
+        match load_certified_key(&self.cert_file, &self.key_file) {
+            Ok(certified_key) => {
+                log::info!("Reloaded TLS certificate: {}", self.cert_file.display());
+                *self.loaded.write().unwrap() = (modified, Arc::new(certified_key));
+            },
+            Err(err) => log::error!("Failed to reload TLS certificate, using the previous one: {}", err),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if self.is_check_due() {
+            // The file system access is blocking, so keep it off the handshake
+            let cert_files = self.cert_files.clone();
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    handle.spawn_blocking(move || cert_files.reload_if_changed());
+                },
+                Err(_) => cert_files.reload_if_changed(),
+            }
+        }
+
+        Some(self.cert_files.loaded.read().unwrap().1.clone())
+    }
+}
+
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn load_certified_key(cert_file: &Path, key_file: &Path) -> ErrorResult<CertifiedKey> {
+    let certs = load_certs(cert_file)?;
+    let key = load_private_key(key_file)?;
+    let signing_key = match rustls::sign::any_supported_type(&key) {
+        Ok(signing_key) => signing_key,
+        Err(_) => return Err(format!("Unsupported private key type in \"{}\".", key_file.display()).into()),
+    };
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn load_certs(path: &Path) -> ErrorResult<Vec<Certificate>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => return Err(format!("Failed to read certificate file \"{}\": {}", path.display(), err).into()),
+    };
+    let certs: Vec<Certificate> = match rustls_pemfile::certs(&mut BufReader::new(file)) {
+        Ok(certs) => certs.into_iter().map(Certificate).collect(),
+        Err(err) => return Err(format!("Failed to parse certificate file \"{}\": {}", path.display(), err).into()),
+    };
+    if certs.is_empty() {
+        return Err(format!("No certificates found in \"{}\".", path.display()).into());
+    }
+
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> ErrorResult<PrivateKey> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => return Err(format!("Failed to read key file \"{}\": {}", path.display(), err).into()),
+    };
+    let items = match rustls_pemfile::read_all(&mut BufReader::new(file)) {
+        Ok(items) => items,
+        Err(err) => return Err(format!("Failed to parse key file \"{}\": {}", path.display(), err).into()),
+    };
+    for item in items.into_iter() {
+        match item {
+            rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {},
+        }
+    }
+
+    Err(format!("No private key found in \"{}\".", path.display()).into())
+}
+
+pub fn load_root_certs(path: &Path) -> ErrorResult<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)?.iter() {
+        if let Err(err) = roots.add(cert) {
+            return Err(format!("Invalid CA certificate in \"{}\": {}", path.display(), err).into());
+        }
+    }
+
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use hyper::header::HeaderValue;
+
+    use super::{DUMMY_PASSWORD_HASH, VALID_CREDENTIALS, build_credentials_key, check_basic_auth, clear_basic_auth_cache, parse_basic_auth};
+
+    fn basic_auth_header(credentials: &str) -> HeaderValue {
+        HeaderValue::from_str(&format!("Basic {}", base64::encode(credentials))).unwrap()
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        for header in ["Bearer YWxpY2U6c2VjcmV0", "basic YWxpY2U6c2VjcmV0", "Basic !!!", "Basic", ""] {
+            assert_eq!(parse_basic_auth(&HeaderValue::from_str(header).unwrap()), None, "{}", header);
+        }
+        // No colon
+        assert_eq!(parse_basic_auth(&basic_auth_header("alicesecret")), None);
+        // Not UTF-8
+        let header = HeaderValue::from_str(&format!("Basic {}", base64::encode([0xff, b':', 0xfe]))).unwrap();
+        assert_eq!(parse_basic_auth(&header), None);
+
+        assert_eq!(parse_basic_auth(&basic_auth_header("alice:secret")), Some(("alice".to_owned(), "secret".to_owned())));
+        // The password may contain colons
+        assert_eq!(parse_basic_auth(&basic_auth_header("alice:sec:ret")), Some(("alice".to_owned(), "sec:ret".to_owned())));
+    }
+
+    #[test]
+    fn dummy_password_hash_is_valid() {
+        // Else unknown users would be rejected faster than known users
+        assert!(DUMMY_PASSWORD_HASH.parse::<bcrypt::HashParts>().is_ok());
+        assert!(bcrypt::verify("secret", DUMMY_PASSWORD_HASH).is_ok());
+    }
+
+    #[tokio::test]
+    async fn basic_auth_is_checked() {
+        // Low cost, for speed
+        let users = BTreeMap::from([("alice".to_owned(), bcrypt::hash("secret", 4).unwrap())]);
+
+        assert!(check_basic_auth(&users, Some(&basic_auth_header("alice:secret"))).await);
+        assert!(!check_basic_auth(&users, Some(&basic_auth_header("alice:wrong"))).await);
+        assert!(!check_basic_auth(&users, Some(&basic_auth_header("alice:"))).await);
+        assert!(!check_basic_auth(&users, Some(&basic_auth_header("bob:secret"))).await);
+        assert!(!check_basic_auth(&users, Some(&basic_auth_header("Alice:secret"))).await);
+        assert!(!check_basic_auth(&users, Some(&HeaderValue::from_static("Basic !!!"))).await);
+        assert!(!check_basic_auth(&users, None).await);
+
+        // Without users, auth is disabled
+        assert!(check_basic_auth(&BTreeMap::new(), None).await);
+    }
+    #[tokio::test]
+    async fn valid_credentials_are_cached() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let users = BTreeMap::from([("carol".to_owned(), hash.clone())]);
+        let credentials_key = build_credentials_key("carol", &hash, "secret");
+
+        assert!(!check_basic_auth(&users, Some(&basic_auth_header("carol:wrong"))).await);
+        assert!(!VALID_CREDENTIALS.lock().unwrap().contains(&build_credentials_key("carol", &hash, "wrong")));
+        assert!(check_basic_auth(&users, Some(&basic_auth_header("carol:secret"))).await);
+        assert!(VALID_CREDENTIALS.lock().unwrap().contains(&credentials_key));
+        assert!(check_basic_auth(&users, Some(&basic_auth_header("carol:secret"))).await);
+
+        // Removed users and changed passwords don't match cached credentials
+        assert!(!check_basic_auth(&BTreeMap::from([("dave".to_owned(), hash.clone())]), Some(&basic_auth_header("carol:secret"))).await);
+        let changed_users = BTreeMap::from([("carol".to_owned(), bcrypt::hash("other", 4).unwrap())]);
+        assert!(!check_basic_auth(&changed_users, Some(&basic_auth_header("carol:secret"))).await);
+
+        clear_basic_auth_cache();
+        assert!(!VALID_CREDENTIALS.lock().unwrap().contains(&credentials_key));
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use hyper::{Body, Method, Request};
+use serde_json::json;
+use url::Url;
+
+use crate::config::Config;
+use crate::http_client::{HttpClient, build_http_client, send_with_retries, split_url_credentials};
+use crate::metrics::{UPS_DESCRIPTION_PSEUDOVAR, UPS_STATUS_METRIC, VarMap};
+use crate::poller::PollResult;
+
+const CONTENT_TYPE_JSON: &str = "application/json";
+
+// Status flags to notify about when set, with the event names.
+const STATUS_EVENTS: [(&str, &str); 5] = [
+    ("OL", "on_line"),
+    ("OB", "on_battery"),
+    ("LB", "low_battery"),
+    ("RB", "replace_battery"),
+    ("FSD", "forced_shutdown"),
+];
+
+type StatusFlags = BTreeSet<String>;
+
+pub struct WebhookClient {
+    client: HttpClient,
+    // URLs without credentials, with the basic auth header values
+    urls: Vec<(Url, Option<String>)>,
+    retries: u32,
+    // Last seen status (raw and flags) per target and UPS
+    statuses: Mutex<HashMap<(String, String), (String, StatusFlags)>>,
+}
+
+impl WebhookClient {
+    pub fn new(config: &Config) -> WebhookClient {
+        WebhookClient {
+            client: build_http_client(),
+            urls: config.webhook_urls.iter().map(split_url_credentials).collect(),
+            retries: config.webhook_retries,
+            statuses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Detect status transitions since the last poll and send events for them in the background.
+    // Nothing is sent for the first status seen for a UPS or for UPSes which failed to be polled, so restarts and
+    // connection problems don't cause duplicate events.
+    pub fn notify(&self, results: &[PollResult]) {
+        let mut statuses = self.statuses.lock().unwrap();
+        for result in results.iter() {
+            for (ups, vars) in result.upses.iter() {
+                let new_status_raw = match vars.get(UPS_STATUS_METRIC.nut_var) {
+                    Some(status_raw) => status_raw,
+                    None => continue,
+                };
+                let new_status = parse_status_flags(new_status_raw);
+                let (old_status_raw, old_status) = match statuses.insert((result.target.clone(), ups.clone()), (new_status_raw.clone(), new_status.clone())) {
+                    Some(old) => old,
+                    None => continue,
+                };
+                let events: Vec<&str> = STATUS_EVENTS.iter()
+                    .filter(|(flag, _)| new_status.contains(*flag) && !old_status.contains(*flag))
+                    .map(|(_, event)| *event)
+                    .collect();
+                if events.is_empty() {
+                    continue;
+                }
+
+                log::info!("UPS \"{}\" on target \"{}\" changed status from \"{}\" to \"{}\".", ups, result.target, old_status_raw, new_status_raw);
+                let payload = build_event_payload(&result.target, ups, vars, &old_status_raw, new_status_raw, &events);
+                self.send_event(payload);
+            }
+        }
+    }
+
+    fn send_event(&self, payload: String) {
+        for (url, authorization) in self.urls.iter() {
+            let client = self.client.clone();
+            let url = url.clone();
+            let authorization = authorization.clone();
+            let payload = payload.clone();
+            let retries = self.retries;
+            tokio::spawn(async move {
+                let build_request = || {
+                    let mut request_builder = Request::builder().method(Method::POST).uri(url.as_str()).header("Content-Type", CONTENT_TYPE_JSON);
+                    if let Some(authorization) = &authorization {
+                        request_builder = request_builder.header("Authorization", authorization);
+                    }
+                    request_builder.body(Body::from(payload.clone()))
+                };
+                let description = format!("Webhook \"{}\"", url);
+                if let Err(err) = send_with_retries(&client, build_request, retries, &description).await {
+                    log::error!("Failed to send status event: {}", err);
+                }
+            });
+        }
+    }
+}
+
+fn parse_status_flags(status_raw: &str) -> StatusFlags {
+    status_raw.split_whitespace().map(|flag| flag.to_owned()).collect()
+}
+
+fn build_event_payload(target: &str, ups: &str, vars: &VarMap, old_status: &str, new_status: &str, events: &[&str]) -> String {
+    let timestamp = chrono::Utc::now();
+    let parse_var = |var: &str| vars.get(var).and_then(|value| value.parse::<f64>().ok());
+    let payload = json!({
+        // Receivers may use the ID to deduplicate retried events
+        "id": format!("{}/{}/{}", target, ups, timestamp.timestamp_millis()),
+        "timestamp": timestamp.to_rfc3339(),
+        "target": target,
+        "ups": ups,
+        "description": vars.get(UPS_DESCRIPTION_PSEUDOVAR),
+        "events": events,
+        "old_status": old_status,
+        "new_status": new_status,
+        "battery_charge": parse_var("battery.charge"),
+        "battery_runtime": parse_var("battery.runtime"),
+    });
+    payload.to_string()
+}